@@ -0,0 +1,16 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS requests
+(
+  id            BIGSERIAL PRIMARY KEY,
+  fid           BIGINT NOT NULL,
+  m_id          CHAR(64) NOT NULL,
+  m_addr        CHAR(64) NOT NULL,
+  m_name        VARCHAR(255) NOT NULL,
+  invite        CHAR(64) NOT NULL,
+  proof         TEXT NOT NULL,
+  is_ok         BOOLEAN NOT NULL DEFAULT FALSE,
+  is_over       BOOLEAN NOT NULL DEFAULT FALSE,
+  datetime      BIGINT  NOT NULL,
+  is_deleted    BOOLEAN NOT NULL DEFAULT FALSE
+);
+CREATE INDEX request_index ON requests (fid, m_id);
@@ -0,0 +1,11 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS group_versions
+(
+  id            BIGSERIAL PRIMARY KEY,
+  fid           BIGINT NOT NULL,
+  g_name        VARCHAR(255) NOT NULL,
+  g_bio         TEXT NOT NULL,
+  is_need_agree BOOLEAN NOT NULL DEFAULT FALSE,
+  datetime      BIGINT  NOT NULL
+);
+CREATE INDEX group_version_index ON group_versions (fid);
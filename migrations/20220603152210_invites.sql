@@ -0,0 +1,11 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS invites
+(
+  id            BIGSERIAL PRIMARY KEY,
+  fid           BIGINT NOT NULL,
+  m_id          CHAR(64) NOT NULL,
+  invite        CHAR(64) NOT NULL,
+  hash          CHAR(64) NOT NULL,
+  datetime      BIGINT  NOT NULL
+);
+CREATE INDEX invite_index ON invites (fid, hash);
@@ -0,0 +1,60 @@
+-- Add migration script here
+-- all models use `groups`, the first migration created `daos`.
+DO $$
+BEGIN
+  IF EXISTS (SELECT FROM pg_tables WHERE tablename = 'daos')
+     AND NOT EXISTS (SELECT FROM pg_tables WHERE tablename = 'groups') THEN
+    ALTER TABLE daos RENAME TO groups;
+  END IF;
+END $$;
+
+-- CHAR is padded with spaces, it will break hex decode and names.
+ALTER TABLE groups ALTER COLUMN key_hash TYPE TEXT;
+ALTER TABLE members ALTER COLUMN m_name TYPE VARCHAR(255);
+
+-- duplicated groups merge to the latest one, their rows move to it.
+CREATE TEMP TABLE group_dups AS
+  SELECT a.id AS old_id, (SELECT MAX(b.id) FROM groups b WHERE b.g_id = a.g_id) AS new_id
+  FROM groups a
+  WHERE a.id < (SELECT MAX(b.id) FROM groups b WHERE b.g_id = a.g_id);
+UPDATE members SET fid = d.new_id FROM group_dups d WHERE members.fid = d.old_id;
+UPDATE messages SET fid = d.new_id FROM group_dups d WHERE messages.fid = d.old_id;
+UPDATE consensus SET fid = d.new_id FROM group_dups d WHERE consensus.fid = d.old_id;
+UPDATE requests SET fid = d.new_id FROM group_dups d WHERE requests.fid = d.old_id;
+UPDATE group_versions SET fid = d.new_id FROM group_dups d WHERE group_versions.fid = d.old_id;
+UPDATE invites SET fid = d.new_id FROM group_dups d WHERE invites.fid = d.old_id;
+DELETE FROM groups USING group_dups d WHERE groups.id = d.old_id;
+DROP TABLE group_dups;
+
+ALTER TABLE groups ADD CONSTRAINT groups_g_id_unique UNIQUE (g_id);
+
+-- duplicated members keep the latest one, messages and heights refer to it.
+CREATE TEMP TABLE member_dups AS
+  SELECT a.id AS old_id, (SELECT MAX(b.id) FROM members b WHERE b.fid = a.fid AND b.m_id = a.m_id) AS new_id
+  FROM members a
+  WHERE a.id < (SELECT MAX(b.id) FROM members b WHERE b.fid = a.fid AND b.m_id = a.m_id);
+UPDATE messages SET mid = d.new_id FROM member_dups d WHERE messages.mid = d.old_id;
+-- heights of GroupTransfer, GroupManagerAdd/Del, MemberInfo, MemberJoin, MemberLeave.
+UPDATE consensus SET cid = d.new_id FROM member_dups d
+  WHERE consensus.cid = d.old_id AND consensus.ctype IN (2, 3, 4, 6, 7, 8);
+DELETE FROM members USING member_dups d WHERE members.id = d.old_id;
+DROP TABLE member_dups;
+
+DROP INDEX IF EXISTS member_index;
+ALTER TABLE members ADD CONSTRAINT members_fid_m_id_unique UNIQUE (fid, m_id);
+ALTER TABLE members ADD CONSTRAINT members_fid_fkey FOREIGN KEY (fid) REFERENCES groups (id);
+
+ALTER TABLE messages ADD CONSTRAINT messages_fid_fkey FOREIGN KEY (fid) REFERENCES groups (id);
+ALTER TABLE messages ADD CONSTRAINT messages_mid_fkey FOREIGN KEY (mid) REFERENCES members (id);
+
+-- duplicated heights keep the latest one.
+DELETE FROM consensus a USING consensus b
+  WHERE a.fid = b.fid AND a.height = b.height AND a.id < b.id;
+
+DROP INDEX IF EXISTS consensus_index;
+ALTER TABLE consensus ADD CONSTRAINT consensus_fid_height_unique UNIQUE (fid, height);
+ALTER TABLE consensus ADD CONSTRAINT consensus_fid_fkey FOREIGN KEY (fid) REFERENCES groups (id);
+
+ALTER TABLE requests ADD CONSTRAINT requests_fid_fkey FOREIGN KEY (fid) REFERENCES groups (id);
+ALTER TABLE group_versions ADD CONSTRAINT group_versions_fid_fkey FOREIGN KEY (fid) REFERENCES groups (id);
+ALTER TABLE invites ADD CONSTRAINT invites_fid_fkey FOREIGN KEY (fid) REFERENCES groups (id);
@@ -0,0 +1,20 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS blobs
+(
+  id            BIGSERIAL PRIMARY KEY,
+  fid           BIGINT NOT NULL REFERENCES groups(id),
+  hash          CHAR(64) NOT NULL,
+  size          BIGINT NOT NULL,
+  refs          BIGINT NOT NULL,
+  datetime      BIGINT  NOT NULL,
+  UNIQUE (fid, hash)
+);
+
+CREATE TABLE IF NOT EXISTS avatars
+(
+  id            BIGSERIAL PRIMARY KEY,
+  fid           BIGINT NOT NULL REFERENCES groups(id),
+  r_id          CHAR(64) NOT NULL,
+  hash          CHAR(64) NOT NULL,
+  UNIQUE (fid, r_id)
+);
@@ -0,0 +1,10 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS uploads
+(
+  id            BIGSERIAL PRIMARY KEY,
+  fid           BIGINT NOT NULL REFERENCES groups(id),
+  mid           BIGINT NOT NULL REFERENCES members(id),
+  size          BIGINT NOT NULL,
+  datetime      BIGINT  NOT NULL
+);
+CREATE INDEX upload_index ON uploads (fid, mid, datetime);
@@ -0,0 +1,5 @@
+-- Add migration script here
+ALTER TABLE members ADD COLUMN delivered BIGINT NOT NULL DEFAULT 0;
+
+-- existing members had synced by themselves, start from current height.
+UPDATE members SET delivered = groups.height FROM groups WHERE members.fid = groups.id;
@@ -0,0 +1,32 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS member_versions
+(
+  id            BIGSERIAL PRIMARY KEY,
+  fid           BIGINT NOT NULL REFERENCES groups(id),
+  m_id          CHAR(64) NOT NULL,
+  m_addr        CHAR(64) NOT NULL,
+  m_name        VARCHAR(255) NOT NULL,
+  avatar        TEXT NOT NULL,
+  datetime      BIGINT  NOT NULL
+);
+CREATE INDEX member_version_index ON member_versions (fid);
+
+-- old MemberInfo heights point to the member row, move them to a version of current info.
+DO $$
+DECLARE
+  r RECORD;
+  vid BIGINT;
+BEGIN
+  FOR r IN
+    SELECT c.id AS cid, m.fid, m.m_id, m.m_addr, m.m_name, m.datetime, TRIM(COALESCE(a.hash, '')) AS avatar
+    FROM consensus c
+    JOIN members m ON m.id = c.cid AND m.fid = c.fid
+    LEFT JOIN avatars a ON a.fid = m.fid AND a.r_id = m.m_id
+    WHERE c.ctype = 6
+  LOOP
+    INSERT INTO member_versions (fid, m_id, m_addr, m_name, avatar, datetime)
+      VALUES (r.fid, r.m_id, r.m_addr, r.m_name, r.avatar, r.datetime) RETURNING id INTO vid;
+    UPDATE consensus SET cid = vid WHERE id = r.cid;
+    UPDATE blobs SET refs = refs + 1 WHERE fid = r.fid AND hash = r.avatar;
+  END LOOP;
+END $$;
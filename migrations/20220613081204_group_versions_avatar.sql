@@ -0,0 +1,13 @@
+-- Add migration script here
+-- avatar's blob hash of the version, empty when no avatar.
+ALTER TABLE group_versions ADD COLUMN avatar TEXT NOT NULL DEFAULT '';
+
+-- old versions only have the current avatar.
+UPDATE group_versions SET avatar = TRIM(a.hash)
+  FROM groups g JOIN avatars a ON a.fid = g.id AND a.r_id = g.g_id
+  WHERE group_versions.fid = g.id;
+
+-- versions keep their avatars, they will not be collected.
+UPDATE blobs SET refs = refs + c.n
+  FROM (SELECT fid, avatar, COUNT(*) AS n FROM group_versions WHERE avatar <> '' GROUP BY fid, avatar) c
+  WHERE blobs.fid = c.fid AND blobs.hash = c.avatar;
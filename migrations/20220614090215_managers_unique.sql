@@ -0,0 +1,6 @@
+-- Add migration script here
+-- duplicated managers keep the latest one.
+DELETE FROM managers a USING managers b
+  WHERE a.gid = b.gid AND a.id < b.id;
+
+ALTER TABLE managers ADD CONSTRAINT managers_gid_unique UNIQUE (gid);
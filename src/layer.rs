@@ -1,20 +1,33 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tdn::types::{
     group::GroupId,
     message::{RecvType, SendType},
     primitive::{HandleResult, PeerAddr, Result},
 };
 
+use tdn_did::Proof;
+
 use group_chat_types::{
     CheckType, ConnectProof, Event, GroupInfo, GroupType, JoinProof, LayerConnect, LayerEvent,
-    LayerResult, GROUP_CHAT_ID,
+    LayerResult, LayerStream, GROUP_CHAT_ID,
 };
 
 use crate::manager::Manager;
-use crate::models::{Consensus, ConsensusType, GroupChat, Member, Message, Request};
-use crate::storage::{delete_avatar, init_local_files, read_avatar, write_avatar};
-use crate::{DEFAULT_REMAIN, NAME, PERMISSIONLESS, SUPPORTED};
+use crate::models::{
+    migrate_legacy_files, Avatar, Blob, Consensus, ConsensusType, GroupChat, GroupVersion, Invite,
+    Member, MemberVersion, Message, Request, Upload,
+};
+use crate::shard::event_group;
+use crate::storage::{
+    append_part, begin, finish_part, init_local_files, limits, part_len, read_blob,
+    read_blob_chunk, read_thumb, Tx,
+};
+use crate::transfer::{hash_hex, Transfer};
+use crate::zkp::{default_verifier, ZkpVerifier};
+use crate::{DEFAULT_REMAIN, NAME, PERMISSIONLESS, STREAM_CHUNK_SIZE, SUPPORTED};
 
 /// Group chat server to ESSE.
 #[inline]
@@ -23,22 +36,136 @@ pub fn add_layer(results: &mut HandleResult, gid: GroupId, msg: SendType) {
 }
 
 pub(crate) struct Layer {
+    /// shard id, groups are sharded to layers which run concurrently.
+    /// it is the high bits of delivery ids and the head of transfer tokens.
+    id: u32,
     base: PathBuf,
+    /// this provider's peer address.
+    addr: PeerAddr,
+    /// anonymous membership proof verifier.
+    zkp: Arc<dyn ZkpVerifier>,
     /// running groups, with members info.
-    /// params: online members (member id, member address, is manager), current height, db id.
-    groups: HashMap<GroupId, (Vec<(GroupId, PeerAddr, bool)>, i64, i64)>,
+    /// params: online devices (member id, network id, device address, is manager),
+    /// current height, db id. events send to the network id, it is member id when not anonymous.
+    /// a member can online with many devices, every device has its entry.
+    groups: HashMap<GroupId, (Vec<(GroupId, GroupId, PeerAddr, bool)>, i64, i64)>,
+    /// closed groups, only history can be synced.
+    /// params: current height, db id.
+    closed: HashMap<GroupId, (i64, i64)>,
+    /// encrypted groups, messages are opaque ciphertext.
+    /// params: the key's hash, joiner must had the same key.
+    encrypted: HashMap<GroupId, Vec<u8>>,
+    /// anonymous members' devices, (group, device address) to (anonymous id, network id).
+    /// device sends events with its network id, it is member by anonymous id in group.
+    anons: HashMap<(GroupId, PeerAddr), (GroupId, GroupId)>,
+    /// online devices index, address to (group, member) which it online.
+    addrs: HashMap<PeerAddr, HashSet<(GroupId, GroupId)>>,
+    /// waiting deliveries, delivery id to
+    /// (group, member, device address, from height, to height, is pushed missed heights).
+    deliveries: HashMap<u64, (GroupId, GroupId, PeerAddr, i64, i64, bool)>,
+    /// waiting deliveries index, (group, member) to device address to delivery ids.
+    waits: HashMap<(GroupId, GroupId), HashMap<PeerAddr, HashSet<u64>>>,
+    /// last delivery id.
+    next_tid: u64,
+    /// online devices' last active time, idle device will be offline.
+    actives: HashMap<PeerAddr, Instant>,
+    /// streamed uploads and downloads, key is the session token.
+    transfers: HashMap<[u8; 32], Transfer>,
 }
 
 impl Layer {
-    pub(crate) async fn new(base: PathBuf) -> Result<Layer> {
+    pub(crate) async fn new(base: PathBuf, addr: PeerAddr) -> Result<Layer> {
+        // old files move to blob store.
+        migrate_legacy_files(&base).await?;
+
         // load groups
         let gs = GroupChat::all().await?;
         let mut groups = HashMap::new();
+        let mut closed = HashMap::new();
+        let mut encrypted = HashMap::new();
         for group in gs {
-            groups.insert(group.g_id, (vec![], group.height, group.id));
+            if group.g_type == GroupType::Encrypted {
+                encrypted.insert(group.g_id, group.key_hash.clone());
+            }
+            if group.is_closed {
+                closed.insert(group.g_id, (group.height, group.id));
+            } else {
+                groups.insert(group.g_id, (vec![], group.height, group.id));
+            }
+        }
+
+        let layer = Layer {
+            id: 0,
+            base,
+            addr,
+            zkp: default_verifier().into(),
+            groups,
+            closed,
+            encrypted,
+            anons: HashMap::new(),
+            addrs: HashMap::new(),
+            deliveries: HashMap::new(),
+            waits: HashMap::new(),
+            next_tid: 0,
+            actives: HashMap::new(),
+            transfers: HashMap::new(),
+        };
+
+        // check consensus heights when start.
+        for (gid, gaps) in layer.check().await? {
+            println!("WARN: group {} consensus missing: {:?}", gid.to_hex(), gaps);
+        }
+
+        Ok(layer)
+    }
+
+    /// new empty layer with the shard id, groups will split or create in it.
+    pub(crate) fn shard(&self, id: u32) -> Layer {
+        Layer::empty(id, self.base.clone(), self.addr, self.zkp.clone())
+    }
+
+    /// layer without any groups, no need database.
+    fn empty(id: u32, base: PathBuf, addr: PeerAddr, zkp: Arc<dyn ZkpVerifier>) -> Layer {
+        Layer {
+            id,
+            base,
+            addr,
+            zkp,
+            groups: HashMap::new(),
+            closed: HashMap::new(),
+            encrypted: HashMap::new(),
+            anons: HashMap::new(),
+            addrs: HashMap::new(),
+            deliveries: HashMap::new(),
+            waits: HashMap::new(),
+            next_tid: 0,
+            actives: HashMap::new(),
+            transfers: HashMap::new(),
+        }
+    }
+
+    /// move the group to a new layer with the shard id, before any device online.
+    pub(crate) fn split(&mut self, gid: &GroupId, id: u32) -> Layer {
+        let mut layer = self.shard(id);
+        if let Some(v) = self.groups.remove(gid) {
+            layer.groups.insert(*gid, v);
+        }
+        if let Some(v) = self.closed.remove(gid) {
+            layer.closed.insert(*gid, v);
+        }
+        if let Some(v) = self.encrypted.remove(gid) {
+            layer.encrypted.insert(*gid, v);
         }
+        layer
+    }
 
-        Ok(Layer { base, groups })
+    /// all groups in this layer, running and closed.
+    pub(crate) fn gids(&self) -> Vec<GroupId> {
+        self.groups
+            .keys()
+            .chain(self.closed.keys())
+            .copied()
+            .collect()
     }
 
     pub(crate) async fn handle(&mut self, gid: GroupId, msg: RecvType) -> Result<HandleResult> {
@@ -49,65 +176,122 @@ impl Layer {
                 let LayerConnect(gcd, connect) = bincode::deserialize(&data)
                     .map_err(|_e| anyhow!("deserialize group chat connect failure"))?;
 
+                // closed group not accept connect.
+                if self.closed.contains_key(&gcd) {
+                    let s = SendType::Result(0, addr, false, false, vec![]);
+                    add_layer(&mut results, gid, s);
+                    return Ok(results);
+                }
+
                 match connect {
                     ConnectProof::Common(proof) => {
                         let (height, fid) = self.height_and_fid(&gcd)?;
                         // check is member.
 
                         if Member::exist(&fid, &gid).await? {
-                            self.add_member(&gcd, gid, addr);
+                            let is_m = Member::is_manager(&fid, &gid).await?;
+                            self.add_member(&gcd, gid, addr, is_m);
                             Self::had_join(height, gcd, gid, addr, &mut results);
 
+                            // push the heights which member missed when offline,
+                            // next batch will push when this batch delivered.
+                            let cursor = Member::cursor(&fid, &gid).await?;
+                            if cursor < height {
+                                let from = cursor + 1;
+                                self.sync_req(gcd, (gid, addr), from, true, true, &mut results)
+                                    .await?;
+                            }
+
+                            // send pending join requests to manager.
+                            if is_m {
+                                self.send_requests(&gcd, &fid, gid, addr, &mut results)
+                                    .await?;
+                            }
+
                             let new_data =
                                 bincode::serialize(&LayerEvent::MemberOnline(gcd, gid, addr))
                                     .map_err(|_| anyhow!("serialize event error."))?;
-                            for (mid, maddr, _) in self.groups(&gcd)? {
+                            for (_, mgid, maddr, _) in self.groups(&gcd)? {
                                 let s = SendType::Event(0, *maddr, new_data.clone());
-                                add_layer(&mut results, *mid, s);
+                                add_layer(&mut results, *mgid, s);
                             }
                         } else {
                             let s = SendType::Result(0, addr, false, false, vec![]);
                             add_layer(&mut results, gid, s);
                         }
                     }
-                    ConnectProof::Zkp(_proof) => {
-                        //
+                    ConnectProof::Zkp(proof) => {
+                        let (height, fid) = self.height_and_fid(&gcd)?;
+
+                        // anonymous member, only known the anonymous id.
+                        let aid = if let Some(aid) = self.zkp_verify(&gcd, &proof) {
+                            aid
+                        } else {
+                            let s = SendType::Result(0, addr, false, false, vec![]);
+                            add_layer(&mut results, gid, s);
+                            return Ok(results);
+                        };
+
+                        if Member::exist(&fid, &aid).await? {
+                            // online with anonymous id, events send to the device address.
+                            self.add_anon(&gcd, aid, gid, addr);
+                            Self::had_join(height, gcd, gid, addr, &mut results);
+                        } else {
+                            let s = SendType::Result(0, addr, false, false, vec![]);
+                            add_layer(&mut results, gid, s);
+                        }
                     }
                 }
             }
             RecvType::Leave(addr) => {
-                for (g, (members, _, _)) in self.groups.iter_mut() {
-                    if let Some(pos) = members.iter().position(|(_, x, _)| x == &addr) {
-                        let (mid, addr, _) = members.remove(pos);
-                        let data = bincode::serialize(&LayerEvent::MemberOffline(*g, mid))
-                            .map_err(|_| anyhow!("serialize event error."))?;
-                        for (mid, maddr, _) in members {
-                            let s = SendType::Event(0, *maddr, data.clone());
-                            add_layer(&mut results, *mid, s);
-                        }
-                    }
-                }
+                self.leave(&addr, &mut results)?;
             }
             RecvType::Event(addr, bytes) => {
+                self.touch(&addr);
                 println!("Got Event");
                 let event: LayerEvent = bincode::deserialize(&bytes)
                     .map_err(|_| anyhow!("deserialize event error."))?;
-                self.handle_event(gid, addr, event, &mut results).await?;
+                let fmid = self.anon_id(&event, gid, &addr);
+                self.handle_event(fmid, gid, addr, event, &mut results)
+                    .await?;
             }
-            RecvType::Stream(_uid, _stream, _bytes) => {
-                // TODO stream
+            RecvType::Stream(uid, stream, bytes) => {
+                let frame: LayerStream = bincode::deserialize(&bytes)
+                    .map_err(|_| anyhow!("deserialize stream error."))?;
+                if let Some(reply) = self.handle_stream(gid, frame).await? {
+                    let data = bincode::serialize(&reply).unwrap_or(vec![]);
+                    add_layer(&mut results, gid, SendType::Stream(uid, stream, data));
+                }
             }
             RecvType::Result(..) => {}        // no-reach here.
             RecvType::ResultConnect(..) => {} // no-reach here.
-            RecvType::Delivery(..) => {}      // no-reach here.
+            RecvType::Delivery(_, tid, is_ok) => {
+                // member received the heights, move its cursor.
+                if let Some((gcd, mid, maddr, from, to, push)) = self.delivered(tid) {
+                    if is_ok {
+                        if let Some((height, fid)) = self.history(&gcd, &mid).await? {
+                            Member::delivered(&fid, &mid, &from, &to).await?;
+
+                            // continue push the missed heights to the device.
+                            let online = self.addrs.get(&maddr).map(|s| s.contains(&(gcd, mid)));
+                            if push && to < height && online == Some(true) {
+                                self.sync_req(gcd, (mid, maddr), to + 1, true, true, &mut results)
+                                    .await?;
+                            }
+                        }
+                    }
+                }
+            }
         }
 
         Ok(results)
     }
 
+    /// fmid: sender's member id, fgid: sender's network id, replies send to it.
     async fn handle_event(
         &mut self,
         fmid: GroupId,
+        fgid: GroupId,
         addr: PeerAddr,
         gevent: LayerEvent,
         results: &mut HandleResult,
@@ -117,16 +301,20 @@ impl Layer {
                 if !self.is_online_member(&gcd, &fmid) {
                     return Ok(());
                 }
-                self.del_member(&gcd, &fmid);
+                // only offline when the last device offline.
+                if !self.del_device(&gcd, &fmid, &addr) {
+                    return Ok(());
+                }
 
                 let new_data = bincode::serialize(&LayerEvent::MemberOffline(gcd, fmid))
                     .map_err(|_| anyhow!("serialize event error."))?;
 
-                for (mid, maddr, _) in self.groups(&gcd)? {
+                for (_, mgid, maddr, _) in self.groups(&gcd)? {
                     let s = SendType::Event(0, *maddr, new_data.clone());
-                    add_layer(results, *mid, s);
+                    add_layer(results, *mgid, s);
                 }
             }
+            LayerEvent::Heartbeat => {} // device is alive, touched.
             LayerEvent::Suspend(gcd) => {
                 // TODO
             }
@@ -152,9 +340,23 @@ impl Layer {
                 let res = LayerEvent::CheckResult(t, NAME.to_owned(), r as i64, SUPPORTED.to_vec());
                 let data = bincode::serialize(&res).unwrap_or(vec![]);
                 let s = SendType::Event(0, addr, data);
-                add_layer(results, fmid, s);
+                add_layer(results, fgid, s);
             }
-            LayerEvent::Create(info, _proof) => {
+            LayerEvent::Create(info, proof) => {
+                // check proof, and owner must be the creator.
+                if !self.check_create(&fmid, &addr, &info, &proof) {
+                    let res = LayerEvent::CheckResult(
+                        CheckType::Deny,
+                        NAME.to_owned(),
+                        0,
+                        SUPPORTED.to_vec(),
+                    );
+                    let data = bincode::serialize(&res).unwrap_or(vec![]);
+                    let s = SendType::Event(0, addr, data);
+                    add_layer(results, fgid, s);
+                    return Ok(());
+                }
+
                 let manager = if let Ok(manager) = Manager::get(&fmid).await {
                     manager
                 } else {
@@ -169,7 +371,7 @@ impl Layer {
                             LayerEvent::CheckResult(CheckType::Deny, "".to_owned(), 0, vec![]);
                         let data = bincode::serialize(&res).unwrap_or(vec![]);
                         let s = SendType::Event(0, addr, data);
-                        add_layer(results, fmid, s);
+                        add_layer(results, fgid, s);
                         return Ok(());
                     }
                 };
@@ -183,11 +385,13 @@ impl Layer {
                     );
                     let data = bincode::serialize(&res).unwrap_or(vec![]);
                     let s = SendType::Event(0, addr, data);
-                    add_layer(results, fmid, s);
+                    add_layer(results, fgid, s);
                     return Ok(());
                 }
 
-                if manager.times == 0 {
+                // use one remain first, parallel creations cannot over the times.
+                let mut tx = begin().await?;
+                if !manager.reduce(&mut tx).await? {
                     let res = LayerEvent::CheckResult(
                         CheckType::None,
                         NAME.to_owned(),
@@ -196,11 +400,10 @@ impl Layer {
                     );
                     let data = bincode::serialize(&res).unwrap_or(vec![]);
                     let s = SendType::Event(0, addr, data);
-                    add_layer(results, fmid, s);
+                    add_layer(results, fgid, s);
                     return Ok(());
                 }
 
-                // TODO check proof.
                 let gcd = match info {
                     GroupInfo::Common(
                         owner,
@@ -215,38 +418,84 @@ impl Layer {
                     ) => {
                         let mut gc = GroupChat::new(owner, gcd, gt, name, bio, need_agree, vec![]);
 
-                        gc.insert().await?;
+                        gc.insert(&mut tx).await?;
 
                         let _ = init_local_files(&self.base, &gc.g_id).await;
-                        let _ = write_avatar(&self.base, &gc.g_id, &gc.g_id, &avatar).await;
+                        Avatar::write(&mut tx, &self.base, &gcd, &gc.id, &gcd, &avatar).await?;
 
                         // add frist member.
                         let mut mem = Member::new(gc.id, owner, addr, owner_name, true);
-                        mem.insert().await?;
+                        mem.insert(&mut tx).await?;
                         // save member avatar.
-                        let _ = write_avatar(&self.base, &gc.g_id, &mem.m_id, &owner_avatar).await;
+                        Avatar::write(&mut tx, &self.base, &gcd, &gc.id, &owner, &owner_avatar)
+                            .await?;
                         println!("add member ok");
 
-                        // reduce manager remain.
-                        let _ = manager.reduce().await;
-
                         self.create_group(gc.id, gcd, fmid, addr);
                         println!("add group ok");
 
-                        self.add_height(&gcd, &mem.id, ConsensusType::MemberJoin)
-                            .await?;
+                        let h = self.add_height(&gcd, &mem.id, ConsensusType::MemberJoin, tx);
+                        if let Err(e) = h.await {
+                            self.drop_group(&gcd);
+                            return Err(e);
+                        }
                         println!("add consensus ok");
                         gcd
                     }
-                    GroupInfo::Encrypted(gcd, ..) => gcd,
+                    GroupInfo::Encrypted(gcd, owner, need_agree, key_hash, info, avatar) => {
+                        // only save the encrypted info, server cannot read it.
+                        let mut gc = GroupChat::new(
+                            owner,
+                            gcd,
+                            GroupType::Encrypted,
+                            "".to_owned(),
+                            hex::encode(&info),
+                            need_agree,
+                            key_hash.clone(),
+                        );
+                        gc.insert(&mut tx).await?;
+
+                        let _ = init_local_files(&self.base, &gc.g_id).await;
+                        Avatar::write(&mut tx, &self.base, &gcd, &gc.id, &gcd, &avatar).await?;
+
+                        // add frist member, member's info will sync by member self.
+                        let mut mem = Member::new(gc.id, owner, addr, "".to_owned(), true);
+                        mem.insert(&mut tx).await?;
+
+                        self.create_group(gc.id, gcd, fmid, addr);
+
+                        let h = self.add_height(&gcd, &mem.id, ConsensusType::MemberJoin, tx);
+                        if let Err(e) = h.await {
+                            self.drop_group(&gcd);
+                            return Err(e);
+                        }
+                        self.encrypted.insert(gcd, key_hash);
+                        gcd
+                    }
                 };
 
                 let res = LayerEvent::CreateResult(gcd, true);
                 let data = bincode::serialize(&res).unwrap_or(vec![]);
                 let s = SendType::Event(0, addr, data);
-                add_layer(results, fmid, s);
+                add_layer(results, fgid, s);
             }
             LayerEvent::Request(gcd, join_proof) => {
+                // closed group not accept join.
+                if self.closed.contains_key(&gcd) {
+                    Self::reject(gcd, fgid, addr, true, results);
+                    return Ok(());
+                }
+
+                // encrypted group's member must had the key, anonymous proof cannot join it.
+                let key_hash = match &join_proof {
+                    JoinProof::Encrypted(_, _, key_hash, ..) => Some(key_hash.clone()),
+                    _ => None,
+                };
+                if !self.check_key(&gcd, &key_hash) {
+                    Self::reject(gcd, fgid, addr, true, results);
+                    return Ok(());
+                }
+
                 // 1. check account is online, if not online, nothing.
                 match join_proof {
                     JoinProof::Open(mname, mavatar) => {
@@ -255,77 +504,65 @@ impl Layer {
 
                         // check is member.
                         if Member::exist(fid, &fmid).await? {
-                            self.add_member(&gcd, fmid, addr);
-                            self.agree(gcd, fmid, addr, group, results).await?;
+                            let is_m = Member::is_manager(fid, &fmid).await?;
+                            self.add_member(&gcd, fmid, addr, is_m);
+                            self.agree(gcd, fgid, addr, group, results).await?;
                             return Ok(());
                         }
 
                         if group.g_type == GroupType::Open {
+                            let mut tx = begin().await?;
                             let mut m = Member::new(*fid, fmid, addr, mname, false);
-                            m.insert().await?;
+                            m.insert(&mut tx).await?;
 
                             // save avatar.
-                            let _ = write_avatar(&self.base, &gcd, &m.m_id, &mavatar).await;
+                            Avatar::write(&mut tx, &self.base, &gcd, fid, &fmid, &mavatar).await?;
 
-                            self.add_member(&gcd, fmid, addr);
-                            self.broadcast_join(&gcd, m, mavatar, results).await?;
+                            let online = (fmid, addr);
+                            self.broadcast_join(&gcd, m, mavatar, online, tx, results)
+                                .await?;
 
                             // return join result.
-                            self.agree(gcd, fmid, addr, group, results).await?;
+                            self.agree(gcd, fgid, addr, group, results).await?;
                         } else {
-                            Self::reject(gcd, fmid, addr, false, results);
+                            Self::reject(gcd, fgid, addr, false, results);
                         }
                     }
                     JoinProof::Invite(invite_gid, proof, mname, mavatar) => {
-                        let fid = self.fid(&gcd)?;
-                        let group = GroupChat::get_id(fid).await?;
-
-                        // check is member.
-                        if Member::exist(fid, &fmid).await? {
-                            self.add_member(&gcd, fmid, addr);
-                            self.agree(gcd, fmid, addr, group, results).await?;
+                        let join = (invite_gid, proof, mname, mavatar);
+                        self.invite_join(gcd, fmid, addr, join, None, results)
+                            .await?;
+                    }
+                    JoinProof::Encrypted(invite_gid, proof, key_hash, mname, mavatar) => {
+                        let join = (invite_gid, proof, mname, mavatar);
+                        self.invite_join(gcd, fmid, addr, join, Some(key_hash), results)
+                            .await?;
+                    }
+                    JoinProof::Zkp(proof) => {
+                        let fid = *self.fid(&gcd)?;
+                        let aid = if let Some(aid) = self.zkp_verify(&gcd, &proof) {
+                            aid
+                        } else {
+                            Self::reject(gcd, fgid, addr, true, results);
                             return Ok(());
-                        }
+                        };
 
-                        // TODO check if request had or is blocked by manager.
-
-                        // check if inviter is member.
-                        if !Member::exist(fid, &invite_gid).await? {
-                            Self::reject(gcd, fmid, addr, true, results);
-                            return Ok(());
-                        }
+                        let group = GroupChat::get_id(&fid).await?;
 
-                        // TODO check proof.
-                        // proof.verify(&invite_gid, &addr, &layer.addr)?;
-
-                        if group.is_need_agree {
-                            if !Member::is_manager(fid, &invite_gid).await? {
-                                let mut request = Request::new();
-                                request.insert().await?;
-                                self.broadcast_request(
-                                    &gcd,
-                                    request,
-                                    JoinProof::Invite(invite_gid, proof, mname, mavatar),
-                                    results,
-                                );
-                                return Ok(());
-                            }
+                        // online first, the join event send to its network id.
+                        self.add_anon(&gcd, aid, fgid, addr);
+
+                        // anonymous member no name and avatar.
+                        if !Member::exist(&fid, &aid).await? {
+                            let mut tx = begin().await?;
+                            let mut m = Member::new(fid, aid, addr, "".to_owned(), false);
+                            m.insert(&mut tx).await?;
+                            let online = (aid, addr);
+                            self.broadcast_join(&gcd, m, vec![], online, tx, results)
+                                .await?;
                         }
 
-                        let mut m = Member::new(*fid, fmid, addr, mname, false);
-                        m.insert().await?;
-
-                        // save avatar.
-                        let _ = write_avatar(&self.base, &gcd, &m.m_id, &mavatar).await;
-
-                        self.add_member(&gcd, fmid, addr);
-                        self.broadcast_join(&gcd, m, mavatar, results).await?;
-
-                        // return join result.
-                        self.agree(gcd, fmid, addr, group, results).await?;
-                    }
-                    JoinProof::Zkp(_proof) => {
-                        // TOOD zkp join.
+                        self.agree(gcd, fgid, addr, group, results).await?;
                     }
                 }
             }
@@ -333,22 +570,37 @@ impl Layer {
                 let fid = self.fid(&gcd)?;
 
                 if Member::is_manager(fid, &fmid).await? {
-                    let request = Request::get(&rid).await?;
-                    if &request.fid == fid {
-                        if ok {
-                            let group = GroupChat::get_id(fid).await?;
+                    let mut request = Request::get(&rid).await?;
+                    if &request.fid != fid || request.is_over {
+                        return Ok(());
+                    }
+                    request.over(ok).await?;
 
-                            let mut m = request.to_member();
-                            m.insert().await?;
+                    if ok {
+                        let group = GroupChat::get_id(fid).await?;
+                        // invite only used when joined.
+                        let mut invite =
+                            Invite::new(request.fid, request.m_id, request.invite, &request.proof)?;
 
-                            self.add_member(&gcd, m.m_id, m.m_addr);
-                            self.agree(gcd, m.m_id, m.m_addr, group, results).await?;
+                        let (m_id, m_addr) = (request.m_id, request.m_addr);
+                        let mavatar = Avatar::read(&self.base, &gcd, fid, &m_id).await?;
 
-                            let mavatar = read_avatar(&self.base, &gcd, &fmid).await?;
-                            self.broadcast_join(&gcd, m, mavatar, results).await?;
-                        } else {
-                            Self::reject(gcd, request.m_id, request.m_addr, true, results);
-                        }
+                        let mut tx = begin().await?;
+                        let mut m = request.to_member();
+                        m.insert(&mut tx).await?;
+                        self.broadcast_join(&gcd, m, mavatar, (m_id, m_addr), tx, results)
+                            .await?;
+                        invite.insert().await?;
+
+                        self.agree(gcd, m_id, m_addr, group, results).await?;
+                    } else {
+                        let mut tx = begin().await?;
+                        Avatar::delete(&mut tx, fid, &request.m_id).await?;
+                        tx.commit()
+                            .await
+                            .map_err(|_| anyhow!("database failure."))?;
+                        let _ = Blob::collect(&self.base, &gcd, fid).await;
+                        Self::reject(gcd, request.m_id, request.m_addr, true, results);
                     }
                     self.broadcast_request_result(&gcd, rid, ok, results);
                 }
@@ -356,81 +608,262 @@ impl Layer {
             LayerEvent::Sync(gcd, _, event) => {
                 println!("Start handle Event.");
 
+                // closed group's owner is offline, reopen it first.
+                if let Event::GroupReopen = &event {
+                    let fid = if let Some((_, fid)) = self.closed.get(&gcd) {
+                        *fid
+                    } else {
+                        return Ok(());
+                    };
+                    if GroupChat::get_id(&fid).await?.owner != fmid {
+                        return Ok(());
+                    }
+
+                    let height = self.reopen_group(&gcd).await?;
+                    let e = LayerEvent::Sync(gcd, height, Event::GroupReopen);
+                    let data = bincode::serialize(&e).unwrap_or(vec![]);
+                    add_layer(results, fgid, SendType::Event(0, addr, data));
+                    return Ok(());
+                }
+
                 if !self.is_online_member(&gcd, &fmid) {
                     return Ok(());
                 }
 
                 let fid = self.fid(&gcd)?;
 
-                let (cid, ctype) = match &event {
-                    Event::GroupInfo => {
-                        // TODO
-                        (0, ConsensusType::GroupInfo)
+                // read first, then event's row, consensus and height commit together.
+                // no pool query when transaction is open, pool connections are limited.
+                let (cid, ctype, tx) = match &event {
+                    Event::GroupInfo(name, bio, avatar, need_agree) => {
+                        if !Member::is_manager(fid, &fmid).await? {
+                            return Ok(());
+                        }
+                        // encrypted group's info is ciphertext, plaintext info cannot overwrite it.
+                        if self.encrypted.contains_key(&gcd) {
+                            println!("reject plaintext info of encrypted group.");
+                            return Ok(());
+                        }
+
+                        let mut group = GroupChat::get_id(fid).await?;
+                        let mut tx = begin().await?;
+                        group
+                            .update_info(&mut tx, name.clone(), bio.clone(), *need_agree)
+                            .await?;
+                        Avatar::write(&mut tx, &self.base, &gcd, fid, &gcd, avatar).await?;
+
+                        // packed history will use the info of this version.
+                        let gavatar = Avatar::get(&mut tx, fid, &gcd).await?.unwrap_or_default();
+                        let (n, b) = (name.clone(), bio.clone());
+                        let mut v = GroupVersion::new(*fid, n, b, *need_agree, gavatar);
+                        v.insert(&mut tx).await?;
+                        (v.id, ConsensusType::GroupInfo, tx)
                     }
-                    Event::GroupTransfer => {
-                        // TODO
-                        (0, ConsensusType::GroupTransfer)
+                    Event::GroupTransfer(new_owner) => {
+                        let mut group = GroupChat::get_id(fid).await?;
+                        if group.owner != fmid || !Member::exist(fid, new_owner).await? {
+                            return Ok(());
+                        }
+
+                        // new owner is manager default.
+                        let mut member = Member::get(fid, new_owner).await?;
+                        let mut tx = begin().await?;
+                        member.set_manager(&mut tx, true).await?;
+                        group.transfer(&mut tx, *new_owner).await?;
+
+                        (member.id, ConsensusType::GroupTransfer, tx)
                     }
-                    Event::GroupManagerAdd => {
-                        // TODO
-                        (0, ConsensusType::GroupManagerAdd)
+                    Event::GroupManagerAdd(mid) => {
+                        let group = GroupChat::get_id(fid).await?;
+                        if group.owner != fmid || !Member::exist(fid, mid).await? {
+                            return Ok(());
+                        }
+
+                        let mut member = Member::get(fid, mid).await?;
+                        let mut tx = begin().await?;
+                        member.set_manager(&mut tx, true).await?;
+
+                        (member.id, ConsensusType::GroupManagerAdd, tx)
                     }
-                    Event::GroupManagerDel => {
-                        // TODO
-                        (0, ConsensusType::GroupManagerDel)
+                    Event::GroupManagerDel(mid) => {
+                        // owner is always manager.
+                        let group = GroupChat::get_id(fid).await?;
+                        if group.owner != fmid || &group.owner == mid {
+                            return Ok(());
+                        }
+                        if !Member::exist(fid, mid).await? {
+                            return Ok(());
+                        }
+
+                        let mut member = Member::get(fid, mid).await?;
+                        let mut tx = begin().await?;
+                        member.set_manager(&mut tx, false).await?;
+
+                        (member.id, ConsensusType::GroupManagerDel, tx)
                     }
                     Event::GroupClose => {
-                        // TODO
-                        (0, ConsensusType::GroupClose)
+                        let mut group = GroupChat::get_id(fid).await?;
+                        if group.owner != fmid {
+                            return Ok(());
+                        }
+
+                        let mut tx = begin().await?;
+                        group.set_closed(&mut tx, true).await?;
+                        (group.id, ConsensusType::GroupClose, tx)
                     }
                     Event::MemberInfo(mid, maddr, mname, mavatar) => {
-                        let member = Member::get(fid, mid).await?;
-                        // TODO
-                        (member.id, ConsensusType::MemberInfo)
+                        // only member self can change info.
+                        if mid != &fmid {
+                            return Ok(());
+                        }
+
+                        let mut member = Member::get(fid, mid).await?;
+                        let mut tx = begin().await?;
+                        member.update_info(&mut tx, *maddr, mname.clone()).await?;
+                        Avatar::write(&mut tx, &self.base, &gcd, fid, mid, mavatar).await?;
+
+                        // packed history will use the info of this version.
+                        let avatar = Avatar::get(&mut tx, fid, mid).await?.unwrap_or_default();
+                        let mut v = MemberVersion::new(*fid, *mid, *maddr, mname.clone(), avatar);
+                        v.insert(&mut tx).await?;
+                        (v.id, ConsensusType::MemberInfo, tx)
                     }
                     Event::MemberLeave(mid) => {
                         let member = Member::get(fid, mid).await?;
-                        member.leave().await?;
-                        let _ = delete_avatar(&self.base, &gcd, &mid).await;
-                        (member.id, ConsensusType::MemberLeave)
+                        let mut tx = begin().await?;
+                        member.leave(&mut tx).await?;
+                        Avatar::delete(&mut tx, fid, &mid).await?;
+                        // left member's invites released, it can be invited again.
+                        Invite::release(&mut tx, fid, &mid).await?;
+                        (member.id, ConsensusType::MemberLeave, tx)
                     }
-                    Event::MessageCreate(mid, nmsg, _) => {
-                        let id =
-                            Message::from_network_message(&self.base, &gcd, fid, mid, nmsg).await?;
-                        (id, ConsensusType::MessageCreate)
+                    Event::MessageCreate(mid, nmsg, mdatetime) => {
+                        let is_encrypted = self.encrypted.contains_key(&gcd);
+                        let member = Member::get(fid, mid).await?;
+                        let check =
+                            Message::check_upload(fid, &member.id, nmsg, is_encrypted).await?;
+                        if let Some(reason) = check {
+                            println!("reject message: {}", reason);
+                            let e = LayerEvent::MessageReject(gcd, *mdatetime, reason);
+                            let data = bincode::serialize(&e).unwrap_or(vec![]);
+                            add_layer(results, fgid, SendType::Event(0, addr, data));
+                            return Ok(());
+                        }
+
+                        let mut tx = begin().await?;
+                        let id = Message::from_network_message(
+                            &mut tx,
+                            &self.base,
+                            &gcd,
+                            fid,
+                            &member.id,
+                            nmsg,
+                            is_encrypted,
+                        )
+                        .await?;
+                        (id, ConsensusType::MessageCreate, tx)
                     }
                     Event::MemberJoin(..) => return Ok(()), // Never here.
+                    Event::GroupReopen => return Ok(()),    // group is running.
+                };
+
+                let height = self.add_height(&gcd, &cid, ctype, tx).await?;
+
+                // saved, change the running group.
+                let is_close = match &event {
+                    Event::GroupTransfer(mid) | Event::GroupManagerAdd(mid) => {
+                        self.set_manager(&gcd, mid, true);
+                        false
+                    }
+                    Event::GroupManagerDel(mid) => {
+                        self.set_manager(&gcd, mid, false);
+                        false
+                    }
+                    Event::GroupInfo(..) | Event::MemberInfo(..) | Event::MemberLeave(..) => {
+                        // old avatar maybe not used.
+                        let fid = self.height_and_fid(&gcd)?.1;
+                        let _ = Blob::collect(&self.base, &gcd, &fid).await;
+                        false
+                    }
+                    Event::GroupClose => true,
+                    _ => false,
+                };
+                let left = match &event {
+                    Event::MemberLeave(mid) => Some(*mid),
+                    _ => None,
                 };
 
-                let height = self.add_height(&gcd, &cid, ctype).await?;
                 println!("Event broadcast");
                 let new_data = bincode::serialize(&LayerEvent::Sync(gcd, height, event))
                     .map_err(|_| anyhow!("serialize event error."))?;
-                for (mid, maddr, _) in self.groups(&gcd)? {
-                    let s = SendType::Event(0, *maddr, new_data.clone());
-                    add_layer(results, *mid, s);
+                self.broadcast_sync(&gcd, height, new_data, results);
+
+                // left member's devices received the event, then offline.
+                if let Some(mid) = left {
+                    self.del_member(&gcd, &mid);
+                }
+                if is_close {
+                    self.close_group(&gcd);
                 }
             }
             LayerEvent::SyncReq(gcd, from) => {
-                if !self.is_online_member(&gcd, &fmid) {
+                self.sync_req(gcd, (fmid, addr), from, false, false, results)
+                    .await?;
+            }
+            LayerEvent::SyncLazyReq(gcd, from) => {
+                self.sync_req(gcd, (fmid, addr), from, true, false, results)
+                    .await?;
+            }
+            LayerEvent::AttachmentReq(gcd, hash) => {
+                let fid = if let Some((_, fid)) = self.history(&gcd, &fmid).await? {
+                    fid
+                } else {
                     return Ok(());
-                }
+                };
+                let hex = hash_hex(&hash)?;
 
-                let (height, fid) = self.height_and_fid(&gcd)?;
-                println!("Got sync request. height: {} from: {}", height, from);
-                if height >= from {
-                    let to = if height - from > 100 {
-                        from + 100
-                    } else {
-                        height
-                    };
-                    let packed = Consensus::pack(&self.base, &gcd, &fid, &from, &to).await?;
-                    let event = LayerEvent::Packed(gcd, height, from, to, packed);
-                    let data = bincode::serialize(&event).unwrap_or(vec![]);
-                    let s = SendType::Event(0, addr, data);
-                    add_layer(results, fmid, s);
-                    println!("Sended sync request results. from: {}, to: {}", from, to);
-                }
+                // large file use stream download.
+                let event = match Blob::size(&fid, &hex).await? {
+                    Some(size) if size <= limits().message_size => {
+                        let bytes = read_blob(&self.base, &gcd, &hex).await?;
+                        LayerEvent::AttachmentResult(gcd, hash, bytes)
+                    }
+                    Some(_) => LayerEvent::TransferReject(gcd, hash, "file too large.".into()),
+                    None => LayerEvent::TransferReject(gcd, hash, "file missing.".into()),
+                };
+                let data = bincode::serialize(&event).unwrap_or(vec![]);
+                add_layer(results, fgid, SendType::Event(0, addr, data));
+            }
+            LayerEvent::ThumbReq(gcd, hash) => {
+                let fid = if let Some((_, fid)) = self.history(&gcd, &fmid).await? {
+                    fid
+                } else {
+                    return Ok(());
+                };
+                let hex = hash_hex(&hash)?;
+
+                // missing thumbnail will regenerate.
+                let thumb = if Message::is_image(&fid, &hex).await? {
+                    read_thumb(&self.base, &gcd, &hex).await.unwrap_or(vec![])
+                } else {
+                    vec![]
+                };
+                let event = LayerEvent::ThumbResult(gcd, hash, thumb);
+                let data = bincode::serialize(&event).unwrap_or(vec![]);
+                add_layer(results, fgid, SendType::Event(0, addr, data));
+            }
+            LayerEvent::MemberAvatar(gcd, mid) => {
+                let fid = if let Some((_, fid)) = self.history(&gcd, &fmid).await? {
+                    fid
+                } else {
+                    return Ok(());
+                };
+
+                let mavatar = Avatar::read(&self.base, &gcd, &fid, &mid).await?;
+                let event = LayerEvent::MemberAvatarResult(gcd, mid, mavatar);
+                let data = bincode::serialize(&event).unwrap_or(vec![]);
+                let s = SendType::Event(0, addr, data);
+                add_layer(results, fgid, s);
             }
             LayerEvent::MemberOnlineSync(gcd) => {
                 if !self.is_online_member(&gcd, &fmid) {
@@ -441,22 +874,374 @@ impl Layer {
                 let event = LayerEvent::MemberOnlineSyncResult(gcd, onlines);
                 let data = bincode::serialize(&event).unwrap_or(vec![]);
                 let s = SendType::Event(0, addr, data);
-                add_layer(results, fmid, s);
+                add_layer(results, fgid, s);
+            }
+            LayerEvent::UploadReq(gcd, hash, size) => {
+                if !self.is_online_member(&gcd, &fmid) {
+                    return Ok(());
+                }
+                let fid = *self.fid(&gcd)?;
+                let hex = hash_hex(&hash)?;
+
+                // empty file no need upload, size saved as i64 in database.
+                let total = match i64::try_from(size) {
+                    Ok(total) if total > 0 => total,
+                    _ => {
+                        let e = LayerEvent::TransferReject(gcd, hash, "size invalid.".into());
+                        let data = bincode::serialize(&e).unwrap_or(vec![]);
+                        add_layer(results, fgid, SendType::Event(0, addr, data));
+                        return Ok(());
+                    }
+                };
+                let member = Member::get(&fid, &fmid).await?;
+
+                // same file had in group, no need upload.
+                let uploaded = if Blob::size(&fid, &hex).await?.is_some() {
+                    size
+                } else {
+                    // streamed file is not a message, has its own limit, quotas are same.
+                    let max = limits().file_size;
+                    let check = Upload::check(&fid, &member.id, total, max).await?;
+                    if let Some(reason) = check {
+                        let e = LayerEvent::TransferReject(gcd, hash, reason);
+                        let data = bincode::serialize(&e).unwrap_or(vec![]);
+                        add_layer(results, fgid, SendType::Event(0, addr, data));
+                        return Ok(());
+                    }
+                    part_len(&self.base, &gcd, &hex).await?
+                };
+
+                let t = Transfer::upload(gcd, fid, member.id, (fgid, addr), hex, size);
+                self.transfer_ready(t, hash, uploaded, results);
+            }
+            LayerEvent::DownloadReq(gcd, hash) => {
+                let fid = if let Some((_, fid)) = self.history(&gcd, &fmid).await? {
+                    fid
+                } else {
+                    return Ok(());
+                };
+                let hex = hash_hex(&hash)?;
+
+                let size = if let Some(size) = Blob::size(&fid, &hex).await? {
+                    size as u64
+                } else {
+                    let e = LayerEvent::TransferReject(gcd, hash, "file missing.".to_owned());
+                    let data = bincode::serialize(&e).unwrap_or(vec![]);
+                    add_layer(results, fgid, SendType::Event(0, addr, data));
+                    return Ok(());
+                };
+
+                let t = Transfer::download(gcd, fid, (fgid, addr), hex, size);
+                self.transfer_ready(t, hash, 0, results);
             }
+            LayerEvent::AttachmentResult(..) => {} // Never here.
+            LayerEvent::ThumbResult(..) => {}      // Never here.
+            LayerEvent::TransferReady(..) => {}    // Never here.
+            LayerEvent::TransferReject(..) => {}   // Never here.
             LayerEvent::MemberOnlineSyncResult(..) => {} // Nerver here.
-            LayerEvent::CheckResult(..) => {}            // Nerver here.
-            LayerEvent::CreateResult(..) => {}           // Nerver here.
-            LayerEvent::RequestHandle(..) => {}          // Nerver here.
-            LayerEvent::Agree(..) => {}                  // Nerver here.
-            LayerEvent::Reject(..) => {}                 // Nerver here.
-            LayerEvent::Packed(..) => {}                 // Nerver here.
-            LayerEvent::MemberOnline(..) => {}           // Nerver here.
-            LayerEvent::MemberOffline(..) => {}          // Never here.
+            LayerEvent::MemberAvatarResult(..) => {} // Nerver here.
+            LayerEvent::CheckResult(..) => {}      // Nerver here.
+            LayerEvent::CreateResult(..) => {}     // Nerver here.
+            LayerEvent::RequestHandle(..) => {}    // Nerver here.
+            LayerEvent::Agree(..) => {}            // Nerver here.
+            LayerEvent::Reject(..) => {}           // Nerver here.
+            LayerEvent::Packed(..) => {}           // Nerver here.
+            LayerEvent::MemberOnline(..) => {}     // Nerver here.
+            LayerEvent::MemberOffline(..) => {}    // Never here.
+            LayerEvent::MessageReject(..) => {}    // Never here.
         }
 
         Ok(())
     }
 
+    /// send the packed history, at most 100 heights once.
+    /// to: the member id and its device address.
+    async fn sync_req(
+        &mut self,
+        gcd: GroupId,
+        to: (GroupId, PeerAddr),
+        from: i64,
+        lazy: bool,
+        push: bool,
+        results: &mut HandleResult,
+    ) -> Result<()> {
+        let (fmid, addr) = to;
+        let (height, fid) = if let Some(v) = self.history(&gcd, &fmid).await? {
+            v
+        } else {
+            return Ok(());
+        };
+        println!("Got sync request. height: {} from: {}", height, from);
+        if height >= from {
+            let to = if height - from > 100 {
+                from + 100
+            } else {
+                height
+            };
+            let packed = Consensus::pack(&self.base, &gcd, &fid, &from, &to, lazy).await?;
+            let event = LayerEvent::Packed(gcd, height, from, to, packed);
+            let data = bincode::serialize(&event).unwrap_or(vec![]);
+            let tid = self.delivery(gcd, fmid, addr, from, to, push);
+            let s = SendType::Event(tid, addr, data);
+            add_layer(results, self.target(&gcd, &fmid, &addr), s);
+            println!("Sended sync request results. from: {}, to: {}", from, to);
+        }
+        Ok(())
+    }
+
+    /// the device offline from all groups.
+    fn leave(&mut self, addr: &PeerAddr, results: &mut HandleResult) -> Result<()> {
+        // only the groups which the address online.
+        let online = self.addrs.get(addr).cloned().unwrap_or_default();
+        for (g, mid) in online {
+            // member still online with other devices.
+            if !self.del_device(&g, &mid, addr) {
+                continue;
+            }
+            let data = bincode::serialize(&LayerEvent::MemberOffline(g, mid))
+                .map_err(|_| anyhow!("serialize event error."))?;
+            for (_, mgid, maddr, _) in self.groups(&g)? {
+                let s = SendType::Event(0, *maddr, data.clone());
+                add_layer(results, *mgid, s);
+            }
+        }
+
+        // uploaded chunks kept, can resume when reconnected.
+        self.transfers.retain(|_, t| &t.addr != addr);
+        Ok(())
+    }
+
+    /// device sent message, it is alive.
+    pub(crate) fn touch(&mut self, addr: &PeerAddr) {
+        if let Some(time) = self.actives.get_mut(addr) {
+            *time = Instant::now();
+        }
+    }
+
+    /// offline the devices which no message in timeout.
+    pub(crate) fn sweep(&mut self, timeout: Duration) -> Result<HandleResult> {
+        let mut results = HandleResult::new();
+        let idles: Vec<PeerAddr> = self
+            .actives
+            .iter()
+            .filter(|(_, time)| time.elapsed() > timeout)
+            .map(|(addr, _)| *addr)
+            .collect();
+
+        for addr in idles {
+            println!("idle timeout: {}", addr.to_hex());
+            self.leave(&addr, &mut results)?;
+        }
+        Ok(results)
+    }
+
+    /// network lost, all devices are offline.
+    pub(crate) fn network_lost(&mut self) {
+        for (members, _, _) in self.groups.values_mut() {
+            members.clear();
+        }
+        self.anons.clear();
+        self.addrs.clear();
+        self.actives.clear();
+        self.deliveries.clear();
+        self.waits.clear();
+        self.transfers.clear();
+    }
+
+    fn transfer_ready(
+        &mut self,
+        transfer: Transfer,
+        hash: Vec<u8>,
+        done: u64,
+        results: &mut HandleResult,
+    ) {
+        let token = transfer.token(self.id);
+        let next = done / STREAM_CHUNK_SIZE;
+        let event = LayerEvent::TransferReady(
+            transfer.gcd,
+            hash,
+            token,
+            transfer.size,
+            STREAM_CHUNK_SIZE,
+            next,
+        );
+        let data = bincode::serialize(&event).unwrap_or(vec![]);
+        add_layer(
+            results,
+            transfer.gid,
+            SendType::Event(0, transfer.addr, data),
+        );
+        self.transfers.insert(token, transfer);
+    }
+
+    /// handle the chunk frames in stream, return the reply frame.
+    async fn handle_stream(
+        &mut self,
+        gid: GroupId,
+        frame: LayerStream,
+    ) -> Result<Option<LayerStream>> {
+        match frame {
+            LayerStream::Upload(token, index, chunk_hash, bytes) => {
+                let (gcd, fid, mid, hash, size, addr) = match self.transfers.get(&token) {
+                    Some(t) if t.gid == gid && t.is_upload => {
+                        (t.gcd, t.fid, t.mid, t.hash.clone(), t.size, t.addr)
+                    }
+                    _ => return Ok(Some(LayerStream::Error(token, "transfer missing.".into()))),
+                };
+                self.touch(&addr);
+
+                // chunks must in order, tell client where to continue.
+                let done = part_len(&self.base, &gcd, &hash).await?;
+                let next = done / STREAM_CHUNK_SIZE;
+                if index != next {
+                    return Ok(Some(LayerStream::Ack(token, next)));
+                }
+
+                if blake3::hash(&bytes).as_bytes() != &chunk_hash {
+                    return Ok(Some(LayerStream::Error(
+                        token,
+                        "chunk hash invalid.".into(),
+                    )));
+                }
+                let end = done + bytes.len() as u64;
+                if end > size || (end < size && bytes.len() as u64 != STREAM_CHUNK_SIZE) {
+                    return Ok(Some(LayerStream::Error(
+                        token,
+                        "chunk size invalid.".into(),
+                    )));
+                }
+
+                // other uploads maybe used the quota, check the received bytes.
+                let check = Upload::check(&fid, &mid, end as i64, limits().file_size).await?;
+                if let Some(reason) = check {
+                    self.transfers.remove(&token);
+                    return Ok(Some(LayerStream::Error(token, reason)));
+                }
+
+                append_part(&self.base, &gcd, &hash, &bytes).await?;
+                if end < size {
+                    return Ok(Some(LayerStream::Ack(token, next + 1)));
+                }
+
+                self.transfers.remove(&token);
+                if !finish_part(&self.base, &gcd, &hash).await? {
+                    return Ok(Some(LayerStream::Error(token, "file hash invalid.".into())));
+                }
+
+                let start = SystemTime::now();
+                let datetime = start
+                    .duration_since(UNIX_EPOCH)
+                    .map(|s| s.as_secs())
+                    .unwrap_or(0) as i64; // safe for all life.
+
+                let mut tx = begin().await?;
+                Blob::add(&mut tx, &fid, &hash, &(size as i64)).await?;
+                Upload::insert(&mut tx, &fid, &mid, &(size as i64), &datetime).await?;
+                tx.commit()
+                    .await
+                    .map_err(|_| anyhow!("database failure."))?;
+
+                Ok(Some(LayerStream::Over(token)))
+            }
+            LayerStream::Download(token, index) => {
+                let (gcd, hash, chunks, addr) = match self.transfers.get(&token) {
+                    Some(t) if t.gid == gid && !t.is_upload => {
+                        (t.gcd, t.hash.clone(), t.chunks(STREAM_CHUNK_SIZE), t.addr)
+                    }
+                    _ => return Ok(Some(LayerStream::Error(token, "transfer missing.".into()))),
+                };
+                self.touch(&addr);
+
+                if index >= chunks {
+                    self.transfers.remove(&token);
+                    return Ok(Some(LayerStream::Over(token)));
+                }
+
+                let offset = index * STREAM_CHUNK_SIZE;
+                let bytes =
+                    read_blob_chunk(&self.base, &gcd, &hash, offset, STREAM_CHUNK_SIZE).await?;
+                let chunk_hash = *blake3::hash(&bytes).as_bytes();
+                Ok(Some(LayerStream::Chunk(token, index, chunk_hash, bytes)))
+            }
+            LayerStream::Ack(..) => Ok(None),   // Never here.
+            LayerStream::Chunk(..) => Ok(None), // Never here.
+            LayerStream::Over(..) => Ok(None),  // Never here.
+            LayerStream::Error(..) => Ok(None), // Never here.
+        }
+    }
+
+    /// plug the anonymous membership proof verifier.
+    pub(crate) fn set_zkp_verifier(&mut self, zkp: Box<dyn ZkpVerifier>) {
+        self.zkp = zkp.into();
+    }
+
+    /// verify the zkp proof, return the anonymous member id.
+    fn zkp_verify(&self, gcd: &GroupId, proof: &[u8]) -> Option<GroupId> {
+        self.zkp.verify(gcd, proof).ok()
+    }
+
+    /// anonymous member's device online in the group, events send to its network id.
+    fn add_anon(&mut self, gcd: &GroupId, aid: GroupId, gid: GroupId, addr: PeerAddr) {
+        self.add_device(gcd, aid, gid, addr, false);
+        self.anons.insert((*gcd, addr), (aid, gid));
+    }
+
+    /// the member id of the event's sender, anonymous device uses its anonymous id.
+    fn anon_id(&self, event: &LayerEvent, gid: GroupId, addr: &PeerAddr) -> GroupId {
+        event_group(event)
+            .and_then(|gcd| self.anons.get(&(gcd, *addr)))
+            .map(|(aid, _)| *aid)
+            .unwrap_or(gid)
+    }
+
+    /// encrypted group need the same key's hash, others no need.
+    fn check_key(&self, gcd: &GroupId, key_hash: &Option<Vec<u8>>) -> bool {
+        match self.encrypted.get(gcd) {
+            Some(hash) => matches!(key_hash, Some(k) if !k.is_empty() && k == hash),
+            None => true,
+        }
+    }
+
+    /// the network id of member's device, anonymous member is not its member id.
+    fn target(&self, gcd: &GroupId, mid: &GroupId, addr: &PeerAddr) -> GroupId {
+        match self.anons.get(&(*gcd, *addr)) {
+            Some((aid, gid)) if aid == mid => *gid,
+            _ => *mid,
+        }
+    }
+
+    /// creator's proof must be signed to this provider, and creator is the owner.
+    fn check_create(
+        &self,
+        fmid: &GroupId,
+        addr: &PeerAddr,
+        info: &GroupInfo,
+        proof: &Proof,
+    ) -> bool {
+        let owner = match info {
+            GroupInfo::Common(owner, ..) => owner,
+            GroupInfo::Encrypted(_, owner, ..) => owner,
+        };
+        if owner != fmid {
+            return false;
+        }
+
+        proof.verify(fmid, addr, &self.addr).is_ok()
+    }
+
+    /// inviter's proof must be signed by inviter, to the joiner's address in this provider.
+    /// used: members which joined by the proof, other member cannot replay it.
+    fn check_invite(
+        &self,
+        invite_gid: &GroupId,
+        addr: &PeerAddr,
+        proof: &Proof,
+        fmid: &GroupId,
+        used: &[GroupId],
+    ) -> bool {
+        proof.verify(invite_gid, addr, &self.addr).is_ok() && used.iter().all(|m| m == fmid)
+    }
+
     fn fid(&self, gid: &GroupId) -> Result<&i64> {
         self.groups
             .get(gid)
@@ -478,7 +1263,23 @@ impl Layer {
             .ok_or(anyhow!("Group missing"))
     }
 
-    fn groups(&self, gid: &GroupId) -> Result<&Vec<(GroupId, PeerAddr, bool)>> {
+    /// if member can read the group's history, return (height, db id).
+    /// closed group is read-only, members can sync history.
+    async fn history(&self, gid: &GroupId, mid: &GroupId) -> Result<Option<(i64, i64)>> {
+        if let Some((height, fid)) = self.closed.get(gid) {
+            if Member::exist(fid, mid).await? {
+                Ok(Some((*height, *fid)))
+            } else {
+                Ok(None)
+            }
+        } else if self.is_online_member(gid, mid) {
+            self.height_and_fid(gid).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn groups(&self, gid: &GroupId) -> Result<&Vec<(GroupId, GroupId, PeerAddr, bool)>> {
         self.groups
             .get(gid)
             .map(|v| &v.0)
@@ -488,56 +1289,192 @@ impl Layer {
     fn onlines(&self, gid: &GroupId) -> Result<Vec<(GroupId, PeerAddr)>> {
         self.groups
             .get(gid)
-            .map(|v| v.0.iter().map(|(g, a, _)| (*g, *a)).collect())
+            .map(|v| v.0.iter().map(|(g, _, a, _)| (*g, *a)).collect())
             .ok_or(anyhow!("Group missing"))
     }
 
     pub fn create_group(&mut self, id: i64, gid: GroupId, rid: GroupId, raddr: PeerAddr) {
-        self.groups.insert(gid, (vec![(rid, raddr, true)], 0, id));
+        self.groups.insert(gid, (vec![], 0, id));
+        self.add_member(&gid, rid, raddr, true);
     }
 
+    /// remove group from running groups, and its online devices.
+    fn drop_group(&mut self, gid: &GroupId) -> Option<(i64, i64)> {
+        let (members, height, fid) = self.groups.remove(gid)?;
+        for (mid, _, maddr, _) in members {
+            self.unindex(&maddr, gid, &mid);
+        }
+        let members: Vec<(GroupId, GroupId)> = self
+            .waits
+            .keys()
+            .filter(|(g, _)| g == gid)
+            .copied()
+            .collect();
+        for (g, mid) in members {
+            self.drop_deliveries(&g, &mid, None);
+        }
+        Some((height, fid))
+    }
+
+    /// remove closed group from running groups.
+    pub fn close_group(&mut self, gid: &GroupId) {
+        if let Some((height, fid)) = self.drop_group(gid) {
+            self.closed.insert(*gid, (height, fid));
+        }
+    }
+
+    /// re-open a closed group, members can connect again.
+    /// it is a new height, so members synced the close will know it.
+    pub(crate) async fn reopen_group(&mut self, gid: &GroupId) -> Result<i64> {
+        let (height, fid) = self
+            .closed
+            .get(gid)
+            .map(|v| (v.0, v.1))
+            .ok_or(anyhow!("Group missing"))?;
+
+        let mut group = GroupChat::get_id(&fid).await?;
+        let mut tx = begin().await?;
+        group.set_closed(&mut tx, false).await?;
+
+        self.closed.remove(gid);
+        self.groups.insert(*gid, (vec![], height, fid));
+        match self
+            .add_height(gid, &group.id, ConsensusType::GroupReopen, tx)
+            .await
+        {
+            Ok(height) => Ok(height),
+            Err(e) => {
+                self.close_group(gid);
+                Err(e)
+            }
+        }
+    }
+
+    /// commit the event's transaction with the new height.
+    /// when failure, the event's row is rollback, and height not changed.
     pub async fn add_height(
         &mut self,
         gid: &GroupId,
         cid: &i64,
         ctype: ConsensusType,
+        mut tx: Tx,
     ) -> Result<i64> {
-        if let Some((_, height, fid)) = self.groups.get_mut(gid) {
-            *height += 1;
-
-            // save.
-            Consensus::insert(fid, height, cid, &ctype).await?;
-            GroupChat::add_height(fid, height).await?;
+        let (height, fid) = self.height_and_fid(gid)?;
+        let height = height + 1;
+
+        // save.
+        Consensus::insert(&mut tx, &fid, &height, cid, &ctype).await?;
+        GroupChat::add_height(&mut tx, &fid, &height).await?;
+        tx.commit()
+            .await
+            .map_err(|_| anyhow!("database failure."))?;
+
+        if let Some((_, h, _)) = self.groups.get_mut(gid) {
+            *h = height;
+        }
+        Ok(height)
+    }
 
-            Ok(*height)
-        } else {
-            Err(anyhow!("Group missing"))
+    /// check all groups' consensus, return the groups which has missing heights.
+    pub(crate) async fn check(&self) -> Result<Vec<(GroupId, Vec<i64>)>> {
+        let mut gaps = vec![];
+        let running = self.groups.iter().map(|(g, v)| (g, v.1, v.2));
+        let closed = self.closed.iter().map(|(g, v)| (g, v.0, v.1));
+        for (gid, height, fid) in running.chain(closed) {
+            let missing = Consensus::gaps(&fid, &height).await?;
+            if !missing.is_empty() {
+                gaps.push((*gid, missing));
+            }
         }
+        Ok(gaps)
+    }
+
+    /// add member's online device, other devices keep online.
+    pub fn add_member(&mut self, gid: &GroupId, rid: GroupId, raddr: PeerAddr, is_manager: bool) {
+        self.add_device(gid, rid, rid, raddr, is_manager)
     }
 
-    pub fn add_member(&mut self, gid: &GroupId, rid: GroupId, raddr: PeerAddr) {
+    /// add member's online device which uses the network id rgid.
+    fn add_device(
+        &mut self,
+        gid: &GroupId,
+        rid: GroupId,
+        rgid: GroupId,
+        raddr: PeerAddr,
+        is_manager: bool,
+    ) {
         if let Some((members, _, _)) = self.groups.get_mut(gid) {
-            for (mid, maddr, is_m) in members.iter_mut() {
+            let mut had = false;
+            for (mid, _, maddr, is_m) in members.iter_mut() {
                 if *mid == rid {
-                    *maddr = raddr;
-                    return;
+                    *is_m = is_manager;
+                    had = had || *maddr == raddr;
                 }
             }
-            members.push((rid, raddr, false));
+            if !had {
+                members.push((rid, rgid, raddr, is_manager));
+                self.addrs.entry(raddr).or_default().insert((*gid, rid));
+            }
+            self.actives.insert(raddr, Instant::now());
         }
     }
 
+    /// remove all devices of the member.
     pub fn del_member(&mut self, gid: &GroupId, rid: &GroupId) {
         if let Some((members, _, _)) = self.groups.get_mut(gid) {
-            if let Some(pos) = members.iter().position(|(mid, _, _)| mid == rid) {
-                members.remove(pos);
+            let mut addrs = vec![];
+            members.retain(|(mid, _, maddr, _)| {
+                if mid == rid {
+                    addrs.push(*maddr);
+                }
+                mid != rid
+            });
+            for addr in addrs {
+                self.unindex(&addr, gid, rid);
+            }
+            self.drop_deliveries(gid, rid, None);
+        }
+    }
+
+    /// remove member's device, return true if it is the last device.
+    pub fn del_device(&mut self, gid: &GroupId, rid: &GroupId, raddr: &PeerAddr) -> bool {
+        if let Some((members, _, _)) = self.groups.get_mut(gid) {
+            members.retain(|(mid, _, maddr, _)| mid != rid || maddr != raddr);
+            let is_last = !members.iter().any(|(mid, ..)| mid == rid);
+            self.unindex(raddr, gid, rid);
+            // offline member's cursor stays, missed heights will push when online.
+            let device = if is_last { None } else { Some(raddr) };
+            self.drop_deliveries(gid, rid, device);
+            is_last
+        } else {
+            false
+        }
+    }
+
+    fn unindex(&mut self, addr: &PeerAddr, gid: &GroupId, rid: &GroupId) {
+        self.anons.remove(&(*gid, *addr));
+        if let Some(online) = self.addrs.get_mut(addr) {
+            online.remove(&(*gid, *rid));
+            if online.is_empty() {
+                self.addrs.remove(addr);
+                self.actives.remove(addr);
+            }
+        }
+    }
+
+    pub fn set_manager(&mut self, gid: &GroupId, rid: &GroupId, is_manager: bool) {
+        if let Some((members, _, _)) = self.groups.get_mut(gid) {
+            for (mid, _, _, is_m) in members.iter_mut() {
+                if mid == rid {
+                    *is_m = is_manager;
+                }
             }
         }
     }
 
     pub fn is_online_member(&self, gid: &GroupId, mid: &GroupId) -> bool {
         if let Some((members, _, _)) = self.groups.get(gid) {
-            for (mmid, _, _) in members {
+            for (mmid, ..) in members {
                 if mmid == mid {
                     return true;
                 }
@@ -547,17 +1484,30 @@ impl Layer {
         false
     }
 
+    /// commit the member's join, and broadcast it.
+    /// online: the new member's network id and address.
     pub async fn broadcast_join(
         &mut self,
         gcd: &GroupId,
         member: Member,
         avatar: Vec<u8>,
+        online: (GroupId, PeerAddr),
+        tx: Tx,
         results: &mut HandleResult,
     ) -> Result<()> {
         println!("start broadcast join...");
-        let height = self
-            .add_height(gcd, &member.id, ConsensusType::MemberJoin)
-            .await?;
+        // new member online first, so it will receive the join event.
+        self.add_member(gcd, online.0, online.1, false);
+        let height = match self
+            .add_height(gcd, &member.id, ConsensusType::MemberJoin, tx)
+            .await
+        {
+            Ok(height) => height,
+            Err(e) => {
+                self.del_device(gcd, &online.0, &online.1);
+                return Err(e);
+            }
+        };
 
         let datetime = member.datetime;
         let event = Event::MemberJoin(
@@ -569,13 +1519,7 @@ impl Layer {
         );
 
         let new_data = bincode::serialize(&LayerEvent::Sync(*gcd, height, event)).unwrap_or(vec![]);
-
-        if let Some((members, _, _)) = self.groups.get(gcd) {
-            for (mid, maddr, _) in members {
-                let s = SendType::Event(0, *maddr, new_data.clone());
-                add_layer(results, *mid, s);
-            }
-        }
+        self.broadcast_sync(gcd, height, new_data, results);
         println!("over broadcast join...");
 
         Ok(())
@@ -600,15 +1544,210 @@ impl Layer {
         let new_data = bincode::serialize(&event).unwrap_or(vec![]);
 
         if let Some((members, _, _)) = self.groups.get(gcd) {
-            for (mid, maddr, is_m) in members {
+            for (_, mgid, maddr, is_m) in members {
                 if *is_m {
                     let s = SendType::Event(0, *maddr, new_data.clone());
-                    add_layer(results, *mid, s);
+                    add_layer(results, *mgid, s);
+                }
+            }
+        }
+    }
+
+    /// broadcast the new height's event, every device will ack it by delivery.
+    fn broadcast_sync(
+        &mut self,
+        gcd: &GroupId,
+        height: i64,
+        data: Vec<u8>,
+        res: &mut HandleResult,
+    ) {
+        let devices: Vec<(GroupId, GroupId, PeerAddr)> =
+            if let Some((members, _, _)) = self.groups.get(gcd) {
+                members
+                    .iter()
+                    .map(|(mid, mgid, maddr, _)| (*mid, *mgid, *maddr))
+                    .collect()
+            } else {
+                return;
+            };
+
+        for (mid, mgid, maddr) in devices {
+            let tid = self.delivery(*gcd, mid, maddr, height, height, false);
+            add_layer(res, mgid, SendType::Event(tid, maddr, data.clone()));
+        }
+    }
+
+    /// wait the delivery of heights (from, to) to the member's device, return the delivery id.
+    /// push: the missed heights pushed by server, it will push next batch when delivered.
+    fn delivery(
+        &mut self,
+        gcd: GroupId,
+        mid: GroupId,
+        addr: PeerAddr,
+        from: i64,
+        to: i64,
+        push: bool,
+    ) -> u64 {
+        // 0 is no delivery, the high bits are the shard id.
+        let seq = (self.next_tid as u32).wrapping_add(1).max(1);
+        self.next_tid = (self.id as u64) << 32 | seq as u64;
+        let delivery = (gcd, mid, addr, from, to, push);
+        // wrapped id maybe still waiting, it will never be delivered.
+        if self.deliveries.contains_key(&self.next_tid) {
+            self.delivered(self.next_tid);
+        }
+        self.deliveries.insert(self.next_tid, delivery);
+        let devices = self.waits.entry((gcd, mid)).or_default();
+        devices.entry(addr).or_default().insert(self.next_tid);
+        self.next_tid
+    }
+
+    /// the device acked the delivery, remove it.
+    fn delivered(&mut self, tid: u64) -> Option<(GroupId, GroupId, PeerAddr, i64, i64, bool)> {
+        let delivery = self.deliveries.remove(&tid)?;
+        let (gcd, mid, addr, ..) = delivery;
+        if let Some(devices) = self.waits.get_mut(&(gcd, mid)) {
+            if let Some(tids) = devices.get_mut(&addr) {
+                tids.remove(&tid);
+                if tids.is_empty() {
+                    devices.remove(&addr);
                 }
             }
+            if devices.is_empty() {
+                self.waits.remove(&(gcd, mid));
+            }
+        }
+        Some(delivery)
+    }
+
+    /// remove the waiting deliveries of the member's device, or all its devices when None.
+    fn drop_deliveries(&mut self, gcd: &GroupId, mid: &GroupId, addr: Option<&PeerAddr>) {
+        let tids: Vec<u64> = if let Some(addr) = addr {
+            let devices = if let Some(devices) = self.waits.get_mut(&(*gcd, *mid)) {
+                devices
+            } else {
+                return;
+            };
+            let tids = devices.remove(addr).unwrap_or_default();
+            if devices.is_empty() {
+                self.waits.remove(&(*gcd, *mid));
+            }
+            tids.into_iter().collect()
+        } else {
+            self.waits
+                .remove(&(*gcd, *mid))
+                .map(|devices| devices.into_values().flatten().collect())
+                .unwrap_or_default()
+        };
+        for tid in tids {
+            self.deliveries.remove(&tid);
         }
     }
 
+    /// join by inviter, encrypted group also need the key's hash.
+    /// join params: inviter, inviter's proof, member name, member avatar.
+    async fn invite_join(
+        &mut self,
+        gcd: GroupId,
+        fmid: GroupId,
+        addr: PeerAddr,
+        join: (GroupId, Proof, String, Vec<u8>),
+        key_hash: Option<Vec<u8>>,
+        results: &mut HandleResult,
+    ) -> Result<()> {
+        let (invite_gid, proof, mname, mavatar) = join;
+        let fid = *self.fid(&gcd)?;
+        let group = GroupChat::get_id(&fid).await?;
+
+        // check is member.
+        if Member::exist(&fid, &fmid).await? {
+            let is_m = Member::is_manager(&fid, &fmid).await?;
+            self.add_member(&gcd, fmid, addr, is_m);
+            self.agree(gcd, fmid, addr, group, results).await?;
+            return Ok(());
+        }
+
+        // encrypted group's member must had the key.
+        if !group.check_key(&key_hash) {
+            Self::reject(gcd, fmid, addr, true, results);
+            return Ok(());
+        }
+
+        // check if inviter is member.
+        if !Member::exist(&fid, &invite_gid).await? {
+            Self::reject(gcd, fmid, addr, true, results);
+            return Ok(());
+        }
+
+        // check inviter's proof, and it only can be used once.
+        let used = Invite::users(&fid, &proof).await?;
+        if !self.check_invite(&invite_gid, &addr, &proof, &fmid, &used) {
+            Self::reject(gcd, fmid, addr, true, results);
+            return Ok(());
+        }
+
+        if group.is_need_agree {
+            if !Member::is_manager(&fid, &invite_gid).await? {
+                // if had pending request, it will be updated.
+                let mut request = Request::new(fid, fmid, addr, mname, invite_gid, proof);
+                request.insert().await?;
+
+                // save avatar, when agree, will use it.
+                let mut tx = begin().await?;
+                Avatar::write(&mut tx, &self.base, &gcd, &fid, &fmid, &mavatar).await?;
+                tx.commit()
+                    .await
+                    .map_err(|_| anyhow!("database failure."))?;
+
+                let join = request.to_join_proof(mavatar);
+                self.broadcast_request(&gcd, request, join, results);
+                return Ok(());
+            }
+        }
+
+        let mut tx = begin().await?;
+        let mut m = Member::new(fid, fmid, addr, mname, false);
+        m.insert(&mut tx).await?;
+
+        // save avatar.
+        Avatar::write(&mut tx, &self.base, &gcd, &fid, &fmid, &mavatar).await?;
+
+        self.broadcast_join(&gcd, m, mavatar, (fmid, addr), tx, results)
+            .await?;
+        if used.is_empty() {
+            Invite::new(fid, fmid, invite_gid, &proof)?.insert().await?;
+        }
+
+        // return join result.
+        self.agree(gcd, fmid, addr, group, results).await
+    }
+
+    /// send all pending requests to the online manager.
+    async fn send_requests(
+        &self,
+        gcd: &GroupId,
+        fid: &i64,
+        mid: GroupId,
+        addr: PeerAddr,
+        results: &mut HandleResult,
+    ) -> Result<()> {
+        for request in Request::pending(fid).await? {
+            let mavatar = Avatar::read(&self.base, gcd, fid, &request.m_id).await?;
+            let event = LayerEvent::RequestHandle(
+                *gcd,
+                request.m_id,
+                request.m_addr,
+                request.to_join_proof(mavatar),
+                request.id,
+                request.datetime,
+            );
+            let data = bincode::serialize(&event).unwrap_or(vec![]);
+            add_layer(results, mid, SendType::Event(0, addr, data));
+        }
+
+        Ok(())
+    }
+
     fn broadcast_request_result(
         &self,
         gcd: &GroupId,
@@ -621,10 +1760,10 @@ impl Layer {
             bincode::serialize(&LayerEvent::RequestResult(*gcd, rid, ok)).unwrap_or(vec![]);
 
         if let Some((members, _, _)) = self.groups.get(gcd) {
-            for (mid, maddr, is_m) in members {
+            for (_, mgid, maddr, is_m) in members {
                 if *is_m {
                     let s = SendType::Event(0, *maddr, new_data.clone());
-                    add_layer(results, *mid, s);
+                    add_layer(results, *mgid, s);
                 }
             }
         }
@@ -651,7 +1790,7 @@ impl Layer {
         group: GroupChat,
         results: &mut HandleResult,
     ) -> Result<()> {
-        let gavatar = read_avatar(&self.base, &gcd, &gcd).await?;
+        let gavatar = Avatar::read(&self.base, &gcd, &group.id, &gcd).await?;
         let group_info = group.to_group_info(gavatar);
         let res = LayerEvent::Agree(gcd, group_info);
         let d = bincode::serialize(&res).unwrap_or(vec![]);
@@ -665,3 +1804,401 @@ impl Layer {
         add_layer(res, gid, SendType::Event(0, addr, d));
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::shard::tid_shard;
+    use tdn_did::{generate_id, Keypair, PublicKey, SecretKey};
+
+    /// provider's address in tests.
+    pub(crate) const PROVIDER: PeerAddr = PeerAddr([9u8; 32]);
+
+    /// deterministic account keypair and its Did.
+    pub(crate) fn account(seed: u8) -> (GroupId, Keypair) {
+        let secret = SecretKey::from_bytes(&[seed; 32]).unwrap();
+        let public: PublicKey = (&secret).into();
+        let gid = generate_id(public.as_bytes());
+        (gid, Keypair { secret, public })
+    }
+
+    pub(crate) fn layer() -> Layer {
+        Layer::empty(
+            0,
+            PathBuf::from("./tests"),
+            PROVIDER,
+            default_verifier().into(),
+        )
+    }
+
+    fn common_info(owner: GroupId) -> GroupInfo {
+        GroupInfo::Common(
+            owner,
+            "owner".to_owned(),
+            vec![],
+            GroupId([1u8; 32]),
+            GroupType::Open,
+            false,
+            "group".to_owned(),
+            "bio".to_owned(),
+            vec![],
+        )
+    }
+
+    /// the events sent to outside.
+    pub(crate) fn events(results: &HandleResult) -> Vec<LayerEvent> {
+        results
+            .layers
+            .iter()
+            .filter_map(|(_, _, msg)| match msg {
+                SendType::Event(_, _, data) => bincode::deserialize(data).ok(),
+                _ => None,
+            })
+            .collect()
+    }
+
+    async fn create(
+        layer: &mut Layer,
+        fmid: GroupId,
+        addr: PeerAddr,
+        info: GroupInfo,
+        proof: Proof,
+    ) -> Vec<LayerEvent> {
+        let mut results = HandleResult::new();
+        let event = LayerEvent::Create(info, proof);
+        layer
+            .handle_event(fmid, fmid, addr, event, &mut results)
+            .await
+            .unwrap();
+        events(&results)
+    }
+
+    #[tokio::test]
+    async fn create_with_mismatched_addr_denied() {
+        let mut layer = layer();
+        let (gid, key) = account(1);
+        let proof = Proof::prove(&key, &PeerAddr([2u8; 32]), &PROVIDER);
+
+        let events = create(
+            &mut layer,
+            gid,
+            PeerAddr([3u8; 32]),
+            common_info(gid),
+            proof,
+        )
+        .await;
+        assert!(matches!(
+            events[..],
+            [LayerEvent::CheckResult(CheckType::Deny, ..)]
+        ));
+    }
+
+    #[tokio::test]
+    async fn create_with_mismatched_owner_denied() {
+        let mut layer = layer();
+        let (gid, key) = account(1);
+        let (other, _) = account(2);
+        let addr = PeerAddr([2u8; 32]);
+        let proof = Proof::prove(&key, &addr, &PROVIDER);
+
+        let events = create(&mut layer, gid, addr, common_info(other), proof).await;
+        assert!(matches!(
+            events[..],
+            [LayerEvent::CheckResult(CheckType::Deny, ..)]
+        ));
+    }
+
+    #[tokio::test]
+    async fn create_with_other_signer_denied() {
+        let mut layer = layer();
+        let (gid, _) = account(1);
+        let (_, other_key) = account(2);
+        let addr = PeerAddr([2u8; 32]);
+        let proof = Proof::prove(&other_key, &addr, &PROVIDER);
+
+        let events = create(&mut layer, gid, addr, common_info(gid), proof).await;
+        assert!(matches!(
+            events[..],
+            [LayerEvent::CheckResult(CheckType::Deny, ..)]
+        ));
+    }
+
+    #[test]
+    fn invite_with_forged_signature_rejected() {
+        let layer = layer();
+        let (inviter, _) = account(1);
+        let (_, forger) = account(2);
+        let addr = PeerAddr([2u8; 32]);
+        let proof = Proof::prove(&forger, &addr, &PROVIDER);
+
+        assert!(!layer.check_invite(&inviter, &addr, &proof, &GroupId([4u8; 32]), &[]));
+    }
+
+    #[test]
+    fn invite_for_other_provider_rejected() {
+        let layer = layer();
+        let (inviter, key) = account(1);
+        let addr = PeerAddr([2u8; 32]);
+        // invite signed to other provider cannot be used here.
+        let proof = Proof::prove(&key, &addr, &PeerAddr([8u8; 32]));
+
+        assert!(!layer.check_invite(&inviter, &addr, &proof, &GroupId([4u8; 32]), &[]));
+    }
+
+    #[test]
+    fn invite_replay_by_other_member_rejected() {
+        let layer = layer();
+        let (inviter, key) = account(1);
+        let addr = PeerAddr([2u8; 32]);
+        let proof = Proof::prove(&key, &addr, &PROVIDER);
+        let (joined, other) = (GroupId([4u8; 32]), GroupId([5u8; 32]));
+
+        // other account in the same device replay the used invite.
+        assert!(!layer.check_invite(&inviter, &addr, &proof, &other, &[joined]));
+    }
+
+    #[test]
+    fn invite_again_by_same_member_accepted() {
+        let layer = layer();
+        let (inviter, key) = account(1);
+        let addr = PeerAddr([2u8; 32]);
+        let proof = Proof::prove(&key, &addr, &PROVIDER);
+        let mid = GroupId([4u8; 32]);
+
+        // signature is deterministic, re-invite is the same proof.
+        assert!(layer.check_invite(&inviter, &addr, &proof, &mid, &[mid]));
+        // member left, its invites released.
+        assert!(layer.check_invite(&inviter, &addr, &proof, &GroupId([5u8; 32]), &[]));
+    }
+
+    #[test]
+    fn invite_with_valid_proof_accepted() {
+        let layer = layer();
+        let (inviter, key) = account(1);
+        let addr = PeerAddr([2u8; 32]);
+        let proof = Proof::prove(&key, &addr, &PROVIDER);
+
+        let mid = GroupId([4u8; 32]);
+        assert!(layer.check_invite(&inviter, &addr, &proof, &mid, &[]));
+        assert!(!layer.check_invite(&inviter, &PeerAddr([3u8; 32]), &proof, &mid, &[]));
+    }
+
+    #[test]
+    fn create_with_valid_proof_accepted() {
+        let layer = layer();
+        let (gid, key) = account(1);
+        let addr = PeerAddr([2u8; 32]);
+        let proof = Proof::prove(&key, &addr, &PROVIDER);
+
+        assert!(layer.check_create(&gid, &addr, &common_info(gid), &proof));
+
+        let encrypted =
+            GroupInfo::Encrypted(GroupId([1u8; 32]), gid, false, vec![], vec![], vec![]);
+        assert!(layer.check_create(&gid, &addr, &encrypted, &proof));
+    }
+
+    pub(crate) fn running_group(layer: &mut Layer) -> GroupId {
+        let gcd = GroupId([1u8; 32]);
+        layer.groups.insert(gcd, (vec![], 0, 1));
+        gcd
+    }
+
+    #[tokio::test]
+    async fn zkp_connect_with_bad_proof_rejected() {
+        let mut layer = layer();
+        let gcd = running_group(&mut layer);
+        let (gid, addr) = (GroupId([2u8; 32]), PeerAddr([2u8; 32]));
+
+        let data = bincode::serialize(&LayerConnect(gcd, ConnectProof::Zkp(vec![]))).unwrap();
+        let results = layer
+            .handle(gid, RecvType::Connect(addr, data))
+            .await
+            .unwrap();
+        assert!(matches!(
+            results.layers[..],
+            [(_, _, SendType::Result(_, _, false, ..))]
+        ));
+        assert!(layer.groups(&gcd).unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn zkp_join_with_bad_proof_rejected() {
+        let mut layer = layer();
+        let gcd = running_group(&mut layer);
+        let (gid, addr) = (GroupId([2u8; 32]), PeerAddr([2u8; 32]));
+
+        let mut results = HandleResult::new();
+        let event = LayerEvent::Request(gcd, JoinProof::Zkp(vec![]));
+        layer
+            .handle_event(gid, gid, addr, event, &mut results)
+            .await
+            .unwrap();
+        assert!(matches!(events(&results)[..], [LayerEvent::Reject(g, true)] if g == gcd));
+        assert!(layer.groups(&gcd).unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn encrypted_join_without_key_rejected() {
+        let mut layer = layer();
+        let gcd = running_group(&mut layer);
+        let key_hash = blake3::hash(&[7u8; 32]).as_bytes().to_vec();
+        layer.encrypted.insert(gcd, key_hash.clone());
+        let (gid, addr) = (GroupId([2u8; 32]), PeerAddr([2u8; 32]));
+        let (inviter, key) = account(1);
+        let proof = Proof::prove(&key, &addr, &PROVIDER);
+
+        let wrong = blake3::hash(&[8u8; 32]).as_bytes().to_vec();
+        let joins = vec![
+            JoinProof::Encrypted(inviter, proof.clone(), wrong, "".into(), vec![]),
+            JoinProof::Encrypted(inviter, proof.clone(), vec![], "".into(), vec![]),
+            JoinProof::Invite(inviter, proof, "".into(), vec![]),
+            // anonymous member cannot prove it had the key.
+            JoinProof::Zkp(vec![1, 2, 3]),
+        ];
+        for join in joins {
+            let mut results = HandleResult::new();
+            let event = LayerEvent::Request(gcd, join);
+            layer
+                .handle_event(gid, gid, addr, event, &mut results)
+                .await
+                .unwrap();
+            assert!(matches!(events(&results)[..], [LayerEvent::Reject(g, true)] if g == gcd));
+            assert!(layer.groups(&gcd).unwrap().is_empty());
+        }
+
+        assert!(layer.check_key(&gcd, &Some(key_hash)));
+        assert!(layer.check_key(&GroupId([3u8; 32]), &None));
+    }
+
+    #[test]
+    fn zkp_anonymous_id_in_group() {
+        let layer = layer();
+        let (g1, g2) = (GroupId([1u8; 32]), GroupId([2u8; 32]));
+
+        let aid = layer.zkp_verify(&g1, &[1, 2, 3]).unwrap();
+        assert_eq!(layer.zkp_verify(&g1, &[1, 2, 3]), Some(aid));
+        assert_ne!(layer.zkp_verify(&g2, &[1, 2, 3]), Some(aid));
+        assert_ne!(layer.zkp_verify(&g1, &[3, 2, 1]), Some(aid));
+        assert_eq!(layer.zkp_verify(&g1, &[]), None);
+    }
+
+    #[test]
+    fn zkp_member_online_with_anonymous_id() {
+        let mut layer = layer();
+        let gcd = running_group(&mut layer);
+        let (gid, addr) = (GroupId([2u8; 32]), PeerAddr([2u8; 32]));
+        let aid = layer.zkp_verify(&gcd, &[1, 2, 3]).unwrap();
+
+        layer.add_anon(&gcd, aid, gid, addr);
+        assert!(layer.is_online_member(&gcd, &aid));
+        assert!(!layer.is_online_member(&gcd, &gid));
+
+        // device's events in the group use the anonymous id.
+        let event = LayerEvent::Offline(gcd);
+        assert_eq!(layer.anon_id(&event, gid, &addr), aid);
+        assert_eq!(layer.anon_id(&LayerEvent::Check, gid, &addr), gid);
+        assert_eq!(layer.anon_id(&event, gid, &PeerAddr([3u8; 32])), gid);
+
+        // offline clean the anonymous index.
+        assert!(layer.del_device(&gcd, &aid, &addr));
+        assert_eq!(layer.anon_id(&event, gid, &addr), gid);
+    }
+
+    #[test]
+    fn zkp_member_events_send_to_network_id() {
+        let mut layer = layer();
+        let gcd = running_group(&mut layer);
+        let (gid, addr) = (GroupId([2u8; 32]), PeerAddr([2u8; 32]));
+        let aid = layer.zkp_verify(&gcd, &[1, 2, 3]).unwrap();
+        let (mid, maddr) = (GroupId([3u8; 32]), PeerAddr([3u8; 32]));
+        layer.add_anon(&gcd, aid, gid, addr);
+        layer.add_member(&gcd, mid, maddr, false);
+
+        let mut results = HandleResult::new();
+        layer.broadcast_sync(&gcd, 1, vec![], &mut results);
+        let mut targets: Vec<(GroupId, PeerAddr)> = results
+            .layers
+            .iter()
+            .filter_map(|(_, tgid, msg)| match msg {
+                SendType::Event(_, taddr, _) => Some((*tgid, *taddr)),
+                _ => None,
+            })
+            .collect();
+        targets.sort_by_key(|(_, a)| a.0);
+        assert_eq!(targets, vec![(gid, addr), (mid, maddr)]);
+
+        // delivery still is the anonymous member's.
+        assert!(layer.deliveries.values().any(|d| d.1 == aid && d.2 == addr));
+        assert_eq!(layer.target(&gcd, &aid, &addr), gid);
+        assert_eq!(layer.target(&gcd, &mid, &maddr), mid);
+    }
+
+    #[test]
+    fn delivery_id_has_shard() {
+        let mut layer = layer();
+        layer.id = 7;
+        let (gcd, mid, addr) = (GroupId([1u8; 32]), GroupId([2u8; 32]), PeerAddr([2u8; 32]));
+
+        let t1 = layer.delivery(gcd, mid, addr, 1, 1, false);
+        let t2 = layer.delivery(gcd, mid, addr, 2, 2, false);
+        assert_eq!(tid_shard(t1), 7);
+        assert_eq!(tid_shard(t2), 7);
+        assert_ne!(t1, t2);
+
+        // sequence wraps, and never is 0.
+        layer.next_tid = 7u64 << 32 | u32::MAX as u64;
+        let t3 = layer.delivery(gcd, mid, addr, 3, 3, false);
+        assert_eq!(t3, 7u64 << 32 | 1);
+        assert_eq!(
+            layer.deliveries.get(&t3),
+            Some(&(gcd, mid, addr, 3, 3, false))
+        );
+    }
+
+    #[test]
+    fn device_offline_drops_its_deliveries() {
+        let mut layer = layer();
+        let gcd = running_group(&mut layer);
+        let mid = GroupId([2u8; 32]);
+        let (a1, a2) = (PeerAddr([2u8; 32]), PeerAddr([3u8; 32]));
+        layer.add_member(&gcd, mid, a1, false);
+        layer.add_member(&gcd, mid, a2, false);
+        let t1 = layer.delivery(gcd, mid, a1, 1, 100, true);
+        let t2 = layer.delivery(gcd, mid, a2, 1, 1, false);
+
+        assert!(!layer.del_device(&gcd, &mid, &a1));
+        assert!(!layer.deliveries.contains_key(&t1));
+        assert!(layer.deliveries.contains_key(&t2));
+
+        assert!(layer.del_device(&gcd, &mid, &a2));
+        assert!(layer.deliveries.is_empty());
+        assert!(layer.waits.is_empty());
+    }
+
+    #[test]
+    fn delivered_and_left_member_unindexed() {
+        let mut layer = layer();
+        let gcd = running_group(&mut layer);
+        let (m1, m2) = (GroupId([2u8; 32]), GroupId([3u8; 32]));
+        let (a1, a2) = (PeerAddr([2u8; 32]), PeerAddr([3u8; 32]));
+        layer.add_member(&gcd, m1, a1, false);
+        layer.add_member(&gcd, m1, a2, false);
+        layer.add_member(&gcd, m2, a2, false);
+        let t1 = layer.delivery(gcd, m1, a1, 1, 1, false);
+        let t2 = layer.delivery(gcd, m1, a2, 1, 1, false);
+        let t3 = layer.delivery(gcd, m2, a2, 1, 1, false);
+
+        assert_eq!(layer.delivered(t1), Some((gcd, m1, a1, 1, 1, false)));
+        assert_eq!(layer.delivered(t1), None);
+        assert!(!layer.waits[&(gcd, m1)].contains_key(&a1));
+
+        layer.del_member(&gcd, &m1);
+        assert!(!layer.deliveries.contains_key(&t2));
+        assert!(!layer.waits.contains_key(&(gcd, m1)));
+        assert!(layer.deliveries.contains_key(&t3));
+
+        layer.drop_group(&gcd);
+        assert!(layer.deliveries.is_empty());
+        assert!(layer.waits.is_empty());
+    }
+}
@@ -9,12 +9,16 @@ mod layer;
 mod manager;
 mod models;
 mod rpc;
+mod shard;
 mod storage;
+mod transfer;
+mod zkp;
 
 use dao_types::{DaoType, DAO_ID};
 use std::env::args;
 use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
 use tdn::{prelude::*, types::primitive::Result};
 use tokio::sync::{mpsc::Sender, RwLock};
 use tracing_subscriber::{filter::LevelFilter, prelude::*};
@@ -35,6 +39,32 @@ pub const PERMISSIONLESS: bool = true;
 /// default number that owner can created groups.
 pub const DEFAULT_REMAIN: i32 = 10;
 
+/// when group's members more than it, packed member avatar is blake3 hash,
+/// client will fetch the avatar bytes by `MemberAvatar`.
+pub const AVATAR_HASH_LIMIT: i64 = 200;
+
+/// default max bytes of one uploaded message. (env: MESSAGE_SIZE_LIMIT)
+pub const DEFAULT_MESSAGE_SIZE_LIMIT: i64 = 50 * 1024 * 1024;
+
+/// default max bytes of one streamed file. (env: FILE_SIZE_LIMIT)
+pub const DEFAULT_FILE_SIZE_LIMIT: i64 = 2 * 1024 * 1024 * 1024;
+
+/// default max bytes of files in one group. (env: GROUP_QUOTA)
+pub const DEFAULT_GROUP_QUOTA: i64 = 10 * 1024 * 1024 * 1024;
+
+/// default max bytes that a member can upload in one day. (env: MEMBER_DAILY_QUOTA)
+pub const DEFAULT_MEMBER_DAILY_QUOTA: i64 = 500 * 1024 * 1024;
+
+/// chunk bytes of streamed upload and download.
+pub const STREAM_CHUNK_SIZE: u64 = 256 * 1024;
+
+/// default seconds that an online device without any message will be offline.
+/// client keeps online by `Heartbeat`. (env: IDLE_TIMEOUT)
+pub const DEFAULT_IDLE_TIMEOUT: u64 = 180;
+
+/// seconds that a streamed upload kept without any message used it.
+pub const UPLOAD_KEEP: i64 = 24 * 60 * 60;
+
 #[tokio::main]
 async fn main() {
     let db_path = args().nth(1).unwrap_or("./.tdn".to_owned());
@@ -81,9 +111,21 @@ pub async fn start(db_path: String) -> Result<()> {
     let (peer_id, sender, mut recver) = start_with_config(config).await.unwrap();
     info!("Network Peer id : {}", peer_id.to_hex());
 
-    let layer = Arc::new(RwLock::new(layer::Layer::new(db_path).await?));
+    let zkp = std::env::var("ZKP_VERIFIER").unwrap_or("none".to_owned());
+    info!("Config zkp verifier: {}", zkp);
 
-    let rpc_handler = rpc::new_rpc_handler(peer_id, layer.clone());
+    let mut layer = layer::Layer::new(db_path, peer_id).await?;
+    layer.set_zkp_verifier(zkp::verifier(&zkp)?);
+    let shards = Arc::new(RwLock::new(shard::Shards::new(layer, sender.clone())));
+
+    let rpc_handler = rpc::new_rpc_handler(peer_id, shards.clone());
+
+    let idle_timeout = std::env::var("IDLE_TIMEOUT")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_IDLE_TIMEOUT);
+    info!("Config idle timeout: {}s", idle_timeout);
+    tokio::spawn(sweep_idle(shards.clone(), idle_timeout));
 
     while let Some(message) = recver.recv().await {
         match message {
@@ -92,9 +134,8 @@ pub async fn start(db_path: String) -> Result<()> {
             }
             ReceiveMessage::Layer(fgid, tgid, l_msg) => {
                 if tgid == GROUP_CHAT_ID {
-                    if let Ok(results) = layer.write().await.handle(fgid, l_msg).await {
-                        handle(results, 0, &sender).await;
-                    }
+                    // handled in the group's shard, results sent by the shard.
+                    shards.write().await.route(fgid, l_msg);
                 }
             }
             ReceiveMessage::Rpc(uid, params, _is_ws) => {
@@ -103,7 +144,7 @@ pub async fn start(db_path: String) -> Result<()> {
                 }
             }
             ReceiveMessage::NetworkLost => {
-                //
+                shards.write().await.network_lost();
             }
         }
     }
@@ -111,6 +152,16 @@ pub async fn start(db_path: String) -> Result<()> {
     Ok(())
 }
 
+/// check the idle devices periodically.
+async fn sweep_idle(shards: Arc<RwLock<shard::Shards>>, timeout: u64) {
+    let timeout = Duration::from_secs(timeout.max(3));
+    let mut interval = tokio::time::interval(timeout / 3);
+    loop {
+        interval.tick().await;
+        shards.write().await.sweep(timeout);
+    }
+}
+
 #[inline]
 async fn handle(handle_result: HandleResult, uid: u64, sender: &Sender<SendMessage>) {
     let HandleResult {
@@ -2,7 +2,7 @@ use std::path::PathBuf;
 use std::time::{SystemTime, UNIX_EPOCH};
 use tdn::types::{group::GroupId, primitive::Result};
 
-use crate::storage::get_pool;
+use crate::storage::{get_pool, Tx};
 
 /// Group Chat Message Model.
 pub(crate) struct Manager {
@@ -74,43 +74,29 @@ impl Manager {
         })
     }
 
+    /// had manager keeps its remain times, parallel inserts are one row.
     pub async fn insert(&mut self) -> Result<()> {
-        let unique_check =
-            sqlx::query!("SELECT id from managers WHERE gid = $1", self.gid.to_hex())
-                .fetch_optional(get_pool()?)
-                .await
-                .map_err(|_| anyhow!("database failure."))?;
-
-        if let Some(rec) = unique_check {
-            self.id = rec.id;
-            let _ = sqlx::query!("UPDATE managers SET is_closed = $1, datetime = $2, is_deleted = false WHERE id = $3",
-                self.is_closed,
-                self.datetime,
-                self.id
-            ).execute(get_pool()?).await.map_err(|_| anyhow!("database failure."))?;
-        } else {
-            let rec = sqlx::query!(
-            "INSERT INTO managers ( gid, times, is_closed, datetime ) VALUES ( $1, $2, $3, $4 ) RETURNING id",
+        let rec = sqlx::query!(
+            "INSERT INTO managers ( gid, times, is_closed, datetime ) VALUES ( $1, $2, $3, $4 ) ON CONFLICT (gid) DO UPDATE SET is_closed = $3, datetime = $4, is_deleted = false RETURNING id, times",
             self.gid.to_hex(),
             self.times,
             self.is_closed,
             self.datetime
         ).fetch_one(get_pool()?).await.map_err(|_| anyhow!("database failure."))?;
-            self.id = rec.id;
-        }
+        self.id = rec.id;
+        self.times = rec.times;
         Ok(())
     }
 
-    pub async fn reduce(&self) -> Result<()> {
-        let _ = sqlx::query!(
-            "UPDATE managers SET times = $1 WHERE id = $2",
-            self.times - 1,
+    /// use one remain time in the creation's transaction, false when no remain.
+    pub async fn reduce(&self, tx: &mut Tx) -> Result<bool> {
+        sqlx::query!(
+            "UPDATE managers SET times = times - 1 WHERE id = $1 AND times > 0 RETURNING times",
             self.id
         )
-        .execute(get_pool()?)
+        .fetch_optional(&mut *tx)
         .await
-        .map_err(|_| anyhow!("database failure."))?;
-
-        Ok(())
+        .map_err(|_| anyhow!("database failure."))
+        .map(|v| v.is_some())
     }
 }
@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 use std::path::PathBuf;
 use std::time::{SystemTime, UNIX_EPOCH};
 use tdn::types::{
@@ -5,12 +6,17 @@ use tdn::types::{
     primitive::{PeerAddr, Result},
 };
 
-use group_chat_types::{GroupInfo, GroupType, NetworkMessage, PackedEvent};
+use tdn_did::Proof;
+
+use group_chat_types::{Attachment, GroupInfo, GroupType, JoinProof, NetworkMessage, PackedEvent};
 
 use crate::storage::{
-    get_pool, read_avatar, read_file, read_image, read_record, write_avatar, write_file,
-    write_image, write_record,
+    begin, delete_blob, get_pool, image_mime, init_local_files, is_blob_name, limits,
+    list_legacy_avatars, parse_record, read_blob, read_blob_chunk, read_legacy, read_thumb,
+    write_blob, write_thumb, Tx, LEGACY_FILES_DIR, LEGACY_IMAGE_DIR, LEGACY_RECORD_DIR,
 };
+use crate::transfer::hash_hex;
+use crate::{AVATAR_HASH_LIMIT, UPLOAD_KEEP};
 
 /// Group Chat Model.
 pub(crate) struct GroupChat {
@@ -26,14 +32,14 @@ pub(crate) struct GroupChat {
     pub g_type: GroupType,
     /// group chat name.
     g_name: String,
-    /// group chat simple intro.
+    /// group chat simple intro. (encrypted group: hex of the encrypted info)
     g_bio: String,
     /// group chat need manager agree.
     pub is_need_agree: bool,
     /// group chat encrypted-key's hash.
-    key_hash: Vec<u8>,
+    pub key_hash: Vec<u8>,
     /// group chat is closed.
-    is_closed: bool,
+    pub is_closed: bool,
     /// group chat created time.
     datetime: i64,
 }
@@ -82,21 +88,28 @@ impl GroupChat {
                 self.g_bio,
                 avatar,
             ),
-            GroupType::Encrypted => GroupInfo::Common(
-                // TODO decode.
-                self.owner,
-                "".to_owned(), // no-need. because in member.
-                vec![],        // owner avatar no-need. because in member.
+            GroupType::Encrypted => GroupInfo::Encrypted(
                 self.g_id,
-                self.g_type,
+                self.owner,
                 self.is_need_agree,
-                self.g_name,
-                self.g_bio,
+                self.key_hash,
+                hex::decode(self.g_bio).unwrap_or(vec![]), // encrypted info.
                 avatar,
             ),
         }
     }
 
+    /// encrypted group need the same key's hash, others no need.
+    pub fn check_key(&self, key_hash: &Option<Vec<u8>>) -> bool {
+        match self.g_type {
+            GroupType::Encrypted => match key_hash {
+                Some(hash) => !hash.is_empty() && hash == &self.key_hash,
+                None => false,
+            },
+            _ => true,
+        }
+    }
+
     pub async fn get_id(id: &i64) -> Result<GroupChat> {
         let res = sqlx::query!(
             "SELECT id, owner, height, g_id, g_type, g_name, g_bio, is_need_agree, key_hash, is_closed, datetime FROM groups WHERE is_deleted = false and id = $1",
@@ -112,7 +125,7 @@ impl GroupChat {
             g_name: res.g_name,
             g_bio: res.g_bio,
             is_need_agree: res.is_need_agree,
-            key_hash: hex::decode(res.key_hash).unwrap_or(vec![]),
+            key_hash: hex::decode(res.key_hash.trim()).unwrap_or(vec![]),
             is_closed: res.is_closed,
             datetime: res.datetime,
         })
@@ -136,7 +149,7 @@ impl GroupChat {
                 g_name: res.g_name,
                 g_bio: res.g_bio,
                 is_need_agree: res.is_need_agree,
-                key_hash: hex::decode(res.key_hash).unwrap_or(vec![]),
+                key_hash: hex::decode(res.key_hash.trim()).unwrap_or(vec![]),
                 is_closed: res.is_closed,
                 datetime: res.datetime,
             });
@@ -145,11 +158,11 @@ impl GroupChat {
         Ok(managers)
     }
 
-    pub async fn insert(&mut self) -> Result<()> {
+    pub async fn insert(&mut self, tx: &mut Tx) -> Result<()> {
         // check if unique group id.
         let unique_check =
             sqlx::query!("SELECT id from groups WHERE g_id = $1", self.g_id.to_hex())
-                .fetch_optional(get_pool()?)
+                .fetch_optional(&mut *tx)
                 .await
                 .map_err(|_| anyhow!("database failure."))?;
         if unique_check.is_some() {
@@ -168,82 +181,276 @@ impl GroupChat {
             hex::encode(&self.key_hash),
             self.is_closed,
             self.datetime
-        ).fetch_one(get_pool()?).await.map_err(|_| anyhow!("database failure."))?;
+        ).fetch_one(&mut *tx).await.map_err(|_| anyhow!("database failure."))?;
 
         self.id = rec.id;
         Ok(())
     }
 
-    pub async fn add_height(id: &i64, height: &i64) -> Result<()> {
+    pub async fn add_height(tx: &mut Tx, id: &i64, height: &i64) -> Result<()> {
         let _ = sqlx::query!("UPDATE groups SET height = $1 WHERE id = $2", height, id)
-            .execute(get_pool()?)
+            .execute(&mut *tx)
             .await
             .map_err(|_| anyhow!("database failure."))?;
 
         Ok(())
     }
+
+    pub async fn update_info(
+        &mut self,
+        tx: &mut Tx,
+        g_name: String,
+        g_bio: String,
+        is_need_agree: bool,
+    ) -> Result<()> {
+        self.g_name = g_name;
+        self.g_bio = g_bio;
+        self.is_need_agree = is_need_agree;
+
+        let _ = sqlx::query!(
+            "UPDATE groups SET g_name = $1, g_bio = $2, is_need_agree = $3 WHERE id = $4",
+            self.g_name,
+            self.g_bio,
+            self.is_need_agree,
+            self.id
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| anyhow!("database failure."))?;
+
+        Ok(())
+    }
+
+    pub async fn set_closed(&mut self, tx: &mut Tx, is_closed: bool) -> Result<()> {
+        self.is_closed = is_closed;
+
+        let _ = sqlx::query!(
+            "UPDATE groups SET is_closed = $1 WHERE id = $2",
+            self.is_closed,
+            self.id
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| anyhow!("database failure."))?;
+
+        Ok(())
+    }
+
+    pub async fn transfer(&mut self, tx: &mut Tx, owner: GroupId) -> Result<()> {
+        self.owner = owner;
+
+        let _ = sqlx::query!(
+            "UPDATE groups SET owner = $1 WHERE id = $2",
+            self.owner.to_hex(),
+            self.id
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| anyhow!("database failure."))?;
+
+        Ok(())
+    }
 }
 
-/// Group Member Model.
-pub(crate) struct Request {
+/// Group Chat Info Version Model. every info change will save a version.
+pub(crate) struct GroupVersion {
     /// db auto-increment id.
     pub id: i64,
     /// group's db id.
-    pub fid: i64,
-    /// member's Did(encrypted/not-encrytped)
-    pub m_id: GroupId,
+    fid: i64,
+    /// group chat name.
+    g_name: String,
+    /// group chat simple intro.
+    g_bio: String,
+    /// group chat need manager agree.
+    is_need_agree: bool,
+    /// avatar's blob hash, empty when no avatar.
+    avatar: String,
+    /// version created time.
+    datetime: i64,
+}
+
+impl GroupVersion {
+    pub fn new(
+        fid: i64,
+        g_name: String,
+        g_bio: String,
+        is_need_agree: bool,
+        avatar: String,
+    ) -> Self {
+        let start = SystemTime::now();
+        let datetime = start
+            .duration_since(UNIX_EPOCH)
+            .map(|s| s.as_secs())
+            .unwrap_or(0) as i64; // safe for all life.
+
+        Self {
+            fid,
+            g_name,
+            g_bio,
+            is_need_agree,
+            avatar,
+            datetime,
+            id: 0,
+        }
+    }
+
+    /// None when it is not found.
+    pub async fn get_id(id: &i64) -> Result<Option<GroupVersion>> {
+        let rec = sqlx::query!(
+            "SELECT id, fid, g_name, g_bio, is_need_agree, avatar, datetime FROM group_versions WHERE id = $1",
+            id,
+        )
+        .fetch_optional(get_pool()?)
+        .await
+        .map_err(|_| anyhow!("database failure."))?;
+
+        Ok(rec.map(|rec| GroupVersion {
+            id: rec.id,
+            fid: rec.fid,
+            g_name: rec.g_name,
+            g_bio: rec.g_bio,
+            is_need_agree: rec.is_need_agree,
+            avatar: rec.avatar.trim().to_owned(),
+            datetime: rec.datetime,
+        }))
+    }
+
+    pub async fn insert(&mut self, tx: &mut Tx) -> Result<()> {
+        // version keeps its avatar, so it will not be collected.
+        if !self.avatar.is_empty() {
+            let hash = hex::decode(&self.avatar).map_err(|_| anyhow!("avatar hash invalid."))?;
+            Blob::refer(tx, &self.fid, &hash).await?;
+        }
+
+        let rec = sqlx::query!(
+            "INSERT INTO group_versions (fid, g_name, g_bio, is_need_agree, avatar, datetime) VALUES ($1, $2, $3, $4, $5, $6) RETURNING id",
+            self.fid,
+            self.g_name,
+            self.g_bio,
+            self.is_need_agree,
+            self.avatar,
+            self.datetime
+        ).fetch_one(&mut *tx).await.map_err(|_| anyhow!("database failure."))?;
+
+        self.id = rec.id;
+        Ok(())
+    }
+}
+
+/// Member Info Version Model. every member info change will save a version.
+pub(crate) struct MemberVersion {
+    /// db auto-increment id.
+    pub id: i64,
+    /// group's db id.
+    fid: i64,
+    /// member's Did.
+    m_id: GroupId,
     /// member's addresse.
-    pub m_addr: PeerAddr,
+    m_addr: PeerAddr,
     /// member's name.
-    pub m_name: String,
-    /// member's joined time.
-    pub datetime: i64,
+    m_name: String,
+    /// avatar's blob hash, empty when no avatar.
+    avatar: String,
+    /// version created time.
+    datetime: i64,
 }
 
-impl Request {
-    pub fn new() -> Request {
-        todo!()
-    }
+impl MemberVersion {
+    pub fn new(fid: i64, m_id: GroupId, m_addr: PeerAddr, m_name: String, avatar: String) -> Self {
+        let start = SystemTime::now();
+        let datetime = start
+            .duration_since(UNIX_EPOCH)
+            .map(|s| s.as_secs())
+            .unwrap_or(0) as i64; // safe for all life.
 
-    pub fn to_member(self) -> Member {
-        todo!()
+        Self {
+            fid,
+            m_id,
+            m_addr,
+            m_name,
+            avatar,
+            datetime,
+            id: 0,
+        }
     }
 
-    pub async fn get(id: &i64) -> Result<Request> {
-        todo!()
+    /// None when it is not found.
+    pub async fn get_id(id: &i64) -> Result<Option<MemberVersion>> {
+        let rec = sqlx::query!(
+            "SELECT id, fid, m_id, m_addr, m_name, avatar, datetime FROM member_versions WHERE id = $1",
+            id,
+        )
+        .fetch_optional(get_pool()?)
+        .await
+        .map_err(|_| anyhow!("database failure."))?;
+
+        Ok(rec.map(|rec| MemberVersion {
+            id: rec.id,
+            fid: rec.fid,
+            m_id: GroupId::from_hex(rec.m_id).unwrap_or(GroupId::default()),
+            m_addr: PeerAddr::from_hex(rec.m_addr).unwrap_or(PeerAddr::default()),
+            m_name: rec.m_name,
+            avatar: rec.avatar.trim().to_owned(),
+            datetime: rec.datetime,
+        }))
     }
 
-    pub async fn insert(&mut self) -> Result<()> {
-        todo!()
+    pub async fn insert(&mut self, tx: &mut Tx) -> Result<()> {
+        // version keeps its avatar, so it will not be collected.
+        if !self.avatar.is_empty() {
+            let hash = hex::decode(&self.avatar).map_err(|_| anyhow!("avatar hash invalid."))?;
+            Blob::refer(tx, &self.fid, &hash).await?;
+        }
+
+        let rec = sqlx::query!(
+            "INSERT INTO member_versions (fid, m_id, m_addr, m_name, avatar, datetime) VALUES ($1, $2, $3, $4, $5, $6) RETURNING id",
+            self.fid,
+            self.m_id.to_hex(),
+            self.m_addr.to_hex(),
+            self.m_name,
+            self.avatar,
+            self.datetime
+        ).fetch_one(&mut *tx).await.map_err(|_| anyhow!("database failure."))?;
+
+        self.id = rec.id;
+        Ok(())
     }
 }
 
-/// Group Member Model.
-pub(crate) struct Member {
+/// Group Join Request Model.
+pub(crate) struct Request {
     /// db auto-increment id.
     pub id: i64,
     /// group's db id.
-    fid: i64,
+    pub fid: i64,
     /// member's Did(encrypted/not-encrytped)
     pub m_id: GroupId,
     /// member's addresse.
     pub m_addr: PeerAddr,
     /// member's name.
     pub m_name: String,
-    /// is group manager.
-    is_manager: bool,
-    /// member's joined time.
+    /// inviter's Did.
+    pub invite: GroupId,
+    /// inviter's proof.
+    pub proof: Proof,
+    /// request is agreed.
+    pub is_ok: bool,
+    /// request is handled by manager.
+    pub is_over: bool,
+    /// request created time.
     pub datetime: i64,
 }
 
-impl Member {
+impl Request {
     pub fn new(
         fid: i64,
         m_id: GroupId,
         m_addr: PeerAddr,
         m_name: String,
-        is_manager: bool,
-    ) -> Self {
+        invite: GroupId,
+        proof: Proof,
+    ) -> Request {
         let start = SystemTime::now();
         let datetime = start
             .duration_since(UNIX_EPOCH)
@@ -252,18 +459,86 @@ impl Member {
 
         Self {
             fid,
-            datetime,
             m_id,
             m_addr,
             m_name,
-            is_manager,
+            invite,
+            proof,
+            datetime,
+            is_ok: false,
+            is_over: false,
             id: 0,
         }
     }
 
+    pub fn to_member(self) -> Member {
+        Member::new(self.fid, self.m_id, self.m_addr, self.m_name, false)
+    }
+
+    /// rebuild the join proof which manager need to handle.
+    pub fn to_join_proof(&self, avatar: Vec<u8>) -> JoinProof {
+        JoinProof::Invite(self.invite, self.proof.clone(), self.m_name.clone(), avatar)
+    }
+
+    pub async fn get(id: &i64) -> Result<Request> {
+        let rec = sqlx::query!(
+            "SELECT id, fid, m_id, m_addr, m_name, invite, proof, is_ok, is_over, datetime FROM requests WHERE id = $1 AND is_deleted = false",
+            id,
+        )
+        .fetch_one(get_pool()?)
+        .await
+        .map_err(|_| anyhow!("database failure."))?;
+
+        Ok(Request {
+            id: rec.id,
+            fid: rec.fid,
+            m_id: GroupId::from_hex(rec.m_id).unwrap_or(GroupId::default()),
+            m_addr: PeerAddr::from_hex(rec.m_addr).unwrap_or(PeerAddr::default()),
+            m_name: rec.m_name,
+            invite: GroupId::from_hex(rec.invite).unwrap_or(GroupId::default()),
+            proof: decode_proof(&rec.proof)?,
+            is_ok: rec.is_ok,
+            is_over: rec.is_over,
+            datetime: rec.datetime,
+        })
+    }
+
+    /// list all requests which waiting manager to handle.
+    pub async fn pending(fid: &i64) -> Result<Vec<Request>> {
+        let recs = sqlx::query!(
+            "SELECT id, fid, m_id, m_addr, m_name, invite, proof, is_ok, is_over, datetime FROM requests WHERE fid = $1 AND is_over = false AND is_deleted = false ORDER BY id",
+            fid,
+        )
+        .fetch_all(get_pool()?)
+        .await
+        .map_err(|_| anyhow!("database failure."))?;
+
+        let mut requests = vec![];
+
+        for rec in recs {
+            requests.push(Request {
+                id: rec.id,
+                fid: rec.fid,
+                m_id: GroupId::from_hex(rec.m_id).unwrap_or(GroupId::default()),
+                m_addr: PeerAddr::from_hex(rec.m_addr).unwrap_or(PeerAddr::default()),
+                m_name: rec.m_name,
+                invite: GroupId::from_hex(rec.invite).unwrap_or(GroupId::default()),
+                proof: decode_proof(&rec.proof)?,
+                is_ok: rec.is_ok,
+                is_over: rec.is_over,
+                datetime: rec.datetime,
+            });
+        }
+
+        Ok(requests)
+    }
+
     pub async fn insert(&mut self) -> Result<()> {
+        let proof = encode_proof(&self.proof)?;
+
+        // one member only has one pending request in a group.
         let unique_check = sqlx::query!(
-            "SELECT id from members WHERE fid = $1 AND m_id = $2",
+            "SELECT id from requests WHERE fid = $1 AND m_id = $2 AND is_over = false AND is_deleted = false",
             self.fid,
             self.m_id.to_hex()
         )
@@ -273,21 +548,25 @@ impl Member {
 
         if let Some(rec) = unique_check {
             self.id = rec.id;
-            let _ = sqlx::query!("UPDATE members SET m_addr = $1, m_name = $2, is_manager = $3, datetime = $4, is_deleted = false WHERE id = $5",
+            let _ = sqlx::query!("UPDATE requests SET m_addr = $1, m_name = $2, invite = $3, proof = $4, datetime = $5 WHERE id = $6",
                 self.m_addr.to_hex(),
                 self.m_name,
-                self.is_manager,
+                self.invite.to_hex(),
+                proof,
                 self.datetime,
                 self.id
             ).execute(get_pool()?).await.map_err(|_| anyhow!("database failure."))?;
         } else {
             let rec = sqlx::query!(
-                "INSERT INTO members (fid, m_id, m_addr, m_name, is_manager, datetime) VALUES ($1, $2, $3, $4, $5, $6) RETURNING id",
+                "INSERT INTO requests (fid, m_id, m_addr, m_name, invite, proof, is_ok, is_over, datetime) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9) RETURNING id",
                 self.fid,
                 self.m_id.to_hex(),
                 self.m_addr.to_hex(),
                 self.m_name,
-                self.is_manager,
+                self.invite.to_hex(),
+                proof,
+                self.is_ok,
+                self.is_over,
                 self.datetime
             ).fetch_one(get_pool()?).await.map_err(|_| anyhow!("database failure."))?;
             self.id = rec.id;
@@ -296,48 +575,246 @@ impl Member {
         Ok(())
     }
 
-    pub async fn exist(fid: &i64, mid: &GroupId) -> Result<bool> {
-        sqlx::query!(
-            "SELECT id FROM members WHERE fid = $1 AND m_id = $2 AND is_deleted = false",
-            fid,
-            mid.to_hex()
-        )
-        .fetch_optional(get_pool()?)
-        .await
-        .map_err(|_| anyhow!("database failure."))
-        .map(|v| v.is_some())
-    }
+    /// manager handled the request.
+    pub async fn over(&mut self, is_ok: bool) -> Result<()> {
+        self.is_ok = is_ok;
+        self.is_over = true;
 
-    pub async fn get_id(id: &i64) -> Result<Member> {
-        let rec = sqlx::query!(
-            "SELECT id, fid, m_id, m_addr, m_name, is_manager, datetime FROM members WHERE id = $1",
-            id,
+        let _ = sqlx::query!(
+            "UPDATE requests SET is_ok = $1, is_over = true WHERE id = $2",
+            self.is_ok,
+            self.id
         )
-        .fetch_one(get_pool()?)
+        .execute(get_pool()?)
         .await
         .map_err(|_| anyhow!("database failure."))?;
 
-        Ok(Member {
-            id: rec.id,
-            fid: rec.fid,
-            m_id: GroupId::from_hex(rec.m_id).unwrap_or(GroupId::default()),
-            m_addr: PeerAddr::from_hex(rec.m_addr).unwrap_or(PeerAddr::default()),
-            m_name: rec.m_name,
-            is_manager: rec.is_manager,
-            datetime: rec.datetime,
-        })
+        Ok(())
     }
+}
+
+/// Used Invite Proof Model. invite proof only can be used by the member which joined by it,
+/// released when the member left.
+pub(crate) struct Invite {
+    /// db auto-increment id.
+    pub id: i64,
+    /// group's db id.
+    fid: i64,
+    /// member's Did.
+    m_id: GroupId,
+    /// inviter's Did.
+    invite: GroupId,
+    /// proof's hash.
+    hash: String,
+    /// proof used time.
+    datetime: i64,
+}
+
+impl Invite {
+    pub fn new(fid: i64, m_id: GroupId, invite: GroupId, proof: &Proof) -> Result<Self> {
+        let start = SystemTime::now();
+        let datetime = start
+            .duration_since(UNIX_EPOCH)
+            .map(|s| s.as_secs())
+            .unwrap_or(0) as i64; // safe for all life.
+
+        Ok(Self {
+            fid,
+            m_id,
+            invite,
+            datetime,
+            hash: proof_hash(proof)?,
+            id: 0,
+        })
+    }
+
+    /// the members which joined by the proof.
+    pub async fn users(fid: &i64, proof: &Proof) -> Result<Vec<GroupId>> {
+        let recs = sqlx::query!(
+            "SELECT m_id FROM invites WHERE fid = $1 AND hash = $2",
+            fid,
+            proof_hash(proof)?
+        )
+        .fetch_all(get_pool()?)
+        .await
+        .map_err(|_| anyhow!("database failure."))?;
+
+        Ok(recs
+            .into_iter()
+            .filter_map(|r| GroupId::from_hex(r.m_id).ok())
+            .collect())
+    }
+
+    /// member left, the proofs it used can be used again.
+    pub async fn release(tx: &mut Tx, fid: &i64, mid: &GroupId) -> Result<()> {
+        let _ = sqlx::query!(
+            "DELETE FROM invites WHERE fid = $1 AND m_id = $2",
+            fid,
+            mid.to_hex()
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| anyhow!("database failure."))?;
+
+        Ok(())
+    }
+
+    pub async fn insert(&mut self) -> Result<()> {
+        let rec = sqlx::query!(
+            "INSERT INTO invites (fid, m_id, invite, hash, datetime) VALUES ($1, $2, $3, $4, $5) RETURNING id",
+            self.fid,
+            self.m_id.to_hex(),
+            self.invite.to_hex(),
+            self.hash,
+            self.datetime
+        ).fetch_one(get_pool()?).await.map_err(|_| anyhow!("database failure."))?;
+
+        self.id = rec.id;
+        Ok(())
+    }
+}
+
+#[inline]
+fn proof_hash(proof: &Proof) -> Result<String> {
+    let bytes = bincode::serialize(proof).map_err(|_| anyhow!("serialize proof error."))?;
+    Ok(blake3::hash(&bytes).to_hex().to_string())
+}
+
+#[inline]
+fn encode_proof(proof: &Proof) -> Result<String> {
+    bincode::serialize(proof)
+        .map(hex::encode)
+        .map_err(|_| anyhow!("serialize proof error."))
+}
+
+#[inline]
+fn decode_proof(s: &str) -> Result<Proof> {
+    let bytes = hex::decode(s.trim()).map_err(|_| anyhow!("proof invalid hex."))?;
+    bincode::deserialize(&bytes).map_err(|_| anyhow!("deserialize proof error."))
+}
+
+/// Group Member Model.
+pub(crate) struct Member {
+    /// db auto-increment id.
+    pub id: i64,
+    /// group's db id.
+    fid: i64,
+    /// member's Did(encrypted/not-encrytped)
+    pub m_id: GroupId,
+    /// member's addresse.
+    pub m_addr: PeerAddr,
+    /// member's name.
+    pub m_name: String,
+    /// is group manager.
+    is_manager: bool,
+    /// member's joined time.
+    pub datetime: i64,
+}
+
+impl Member {
+    pub fn new(
+        fid: i64,
+        m_id: GroupId,
+        m_addr: PeerAddr,
+        m_name: String,
+        is_manager: bool,
+    ) -> Self {
+        let start = SystemTime::now();
+        let datetime = start
+            .duration_since(UNIX_EPOCH)
+            .map(|s| s.as_secs())
+            .unwrap_or(0) as i64; // safe for all life.
+
+        Self {
+            fid,
+            datetime,
+            m_id,
+            m_addr,
+            m_name,
+            is_manager,
+            id: 0,
+        }
+    }
+
+    pub async fn insert(&mut self, tx: &mut Tx) -> Result<()> {
+        let unique_check = sqlx::query!(
+            "SELECT id from members WHERE fid = $1 AND m_id = $2",
+            self.fid,
+            self.m_id.to_hex()
+        )
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(|_| anyhow!("database failure."))?;
+
+        if let Some(rec) = unique_check {
+            self.id = rec.id;
+            // rejoin, the heights when it left no need deliver.
+            let _ = sqlx::query!("UPDATE members SET m_addr = $1, m_name = $2, is_manager = $3, datetime = $4, is_deleted = false, delivered = (SELECT height + 1 FROM groups WHERE id = fid) WHERE id = $5",
+                self.m_addr.to_hex(),
+                self.m_name,
+                self.is_manager,
+                self.datetime,
+                self.id
+            ).execute(&mut *tx).await.map_err(|_| anyhow!("database failure."))?;
+        } else {
+            let rec = sqlx::query!(
+                "INSERT INTO members (fid, m_id, m_addr, m_name, is_manager, datetime, delivered) VALUES ($1, $2, $3, $4, $5, $6, (SELECT height + 1 FROM groups WHERE id = $1)) RETURNING id",
+                self.fid,
+                self.m_id.to_hex(),
+                self.m_addr.to_hex(),
+                self.m_name,
+                self.is_manager,
+                self.datetime
+            ).fetch_one(&mut *tx).await.map_err(|_| anyhow!("database failure."))?;
+            self.id = rec.id;
+        }
+
+        Ok(())
+    }
+
+    pub async fn exist(fid: &i64, mid: &GroupId) -> Result<bool> {
+        sqlx::query!(
+            "SELECT id FROM members WHERE fid = $1 AND m_id = $2 AND is_deleted = false",
+            fid,
+            mid.to_hex()
+        )
+        .fetch_optional(get_pool()?)
+        .await
+        .map_err(|_| anyhow!("database failure."))
+        .map(|v| v.is_some())
+    }
+
+    /// None when it is not found.
+    pub async fn get_id(id: &i64) -> Result<Option<Member>> {
+        let rec = sqlx::query!(
+            "SELECT id, fid, m_id, m_addr, m_name, is_manager, datetime FROM members WHERE id = $1",
+            id,
+        )
+        .fetch_optional(get_pool()?)
+        .await
+        .map_err(|_| anyhow!("database failure."))?;
+
+        Ok(rec.map(|rec| Member {
+            id: rec.id,
+            fid: rec.fid,
+            m_id: GroupId::from_hex(rec.m_id).unwrap_or(GroupId::default()),
+            m_addr: PeerAddr::from_hex(rec.m_addr).unwrap_or(PeerAddr::default()),
+            m_name: rec.m_name,
+            is_manager: rec.is_manager,
+            datetime: rec.datetime,
+        }))
+    }
+
+    pub async fn get(fid: &i64, gid: &GroupId) -> Result<Member> {
+        let rec = sqlx::query!(
+            "SELECT id, fid, m_id, m_addr, m_name, is_manager, datetime FROM members WHERE fid = $1 AND m_id = $2 AND is_deleted = false",
+            fid,
+            gid.to_hex(),
+        )
+        .fetch_one(get_pool()?)
+        .await
+        .map_err(|_| anyhow!("database failure."))?;
 
-    pub async fn get(fid: &i64, gid: &GroupId) -> Result<Member> {
-        let rec = sqlx::query!(
-            "SELECT id, fid, m_id, m_addr, m_name, is_manager, datetime FROM members WHERE fid = $1 AND m_id = $2 AND is_deleted = false",
-            fid,
-            gid.to_hex(),
-        )
-        .fetch_one(get_pool()?)
-        .await
-        .map_err(|_| anyhow!("database failure."))?;
-
         Ok(Member {
             id: rec.id,
             fid: rec.fid,
@@ -349,11 +826,92 @@ impl Member {
         })
     }
 
-    pub async fn leave(&self) -> Result<()> {
+    pub async fn leave(&self, tx: &mut Tx) -> Result<()> {
         let _ = sqlx::query!(
             "UPDATE members SET is_deleted = true WHERE id = $1",
             self.id
         )
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| anyhow!("database failure."))?;
+
+        Ok(())
+    }
+
+    pub async fn update_info(
+        &mut self,
+        tx: &mut Tx,
+        m_addr: PeerAddr,
+        m_name: String,
+    ) -> Result<()> {
+        self.m_addr = m_addr;
+        self.m_name = m_name;
+
+        let _ = sqlx::query!(
+            "UPDATE members SET m_addr = $1, m_name = $2 WHERE id = $3",
+            self.m_addr.to_hex(),
+            self.m_name,
+            self.id
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| anyhow!("database failure."))?;
+
+        Ok(())
+    }
+
+    pub async fn set_manager(&mut self, tx: &mut Tx, is_manager: bool) -> Result<()> {
+        self.is_manager = is_manager;
+
+        let _ = sqlx::query!(
+            "UPDATE members SET is_manager = $1 WHERE id = $2",
+            self.is_manager,
+            self.id
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| anyhow!("database failure."))?;
+
+        Ok(())
+    }
+
+    pub async fn count(fid: &i64) -> Result<i64> {
+        let rec = sqlx::query!(
+            "SELECT COUNT(*) AS count FROM members WHERE fid = $1 AND is_deleted = false",
+            fid
+        )
+        .fetch_one(get_pool()?)
+        .await
+        .map_err(|_| anyhow!("database failure."))?;
+
+        Ok(rec.count.unwrap_or(0))
+    }
+
+    /// last height which the member received, start from the join height.
+    /// it is member's cursor, not device's: any device acked, other devices will not be pushed,
+    /// they sync the history by `SyncReq` themselves.
+    pub async fn cursor(fid: &i64, mid: &GroupId) -> Result<i64> {
+        let rec = sqlx::query!(
+            "SELECT delivered FROM members WHERE fid = $1 AND m_id = $2 AND is_deleted = false",
+            fid,
+            mid.to_hex()
+        )
+        .fetch_optional(get_pool()?)
+        .await
+        .map_err(|_| anyhow!("database failure."))?;
+
+        Ok(rec.map(|r| r.delivered).unwrap_or(0))
+    }
+
+    /// member received heights (from, to), only move the cursor when no gap.
+    pub async fn delivered(fid: &i64, mid: &GroupId, from: &i64, to: &i64) -> Result<()> {
+        let _ = sqlx::query!(
+            "UPDATE members SET delivered = $1 WHERE fid = $2 AND m_id = $3 AND delivered >= $4 - 1 AND delivered < $1",
+            to,
+            fid,
+            mid.to_hex(),
+            from
+        )
         .execute(get_pool()?)
         .await
         .map_err(|_| anyhow!("database failure."))?;
@@ -391,6 +949,7 @@ pub(crate) enum MessageType {
     Phone,
     Video,
     Invite,
+    Encrypted,
 }
 
 impl MessageType {
@@ -405,6 +964,7 @@ impl MessageType {
             MessageType::Phone => 6,
             MessageType::Video => 7,
             MessageType::Invite => 8,
+            MessageType::Encrypted => 9,
         }
     }
 
@@ -419,6 +979,7 @@ impl MessageType {
             6 => MessageType::Phone,
             7 => MessageType::Video,
             8 => MessageType::Invite,
+            9 => MessageType::Encrypted,
             _ => MessageType::String,
         }
     }
@@ -441,12 +1002,47 @@ pub(crate) struct Message {
 }
 
 impl Message {
+    /// check the upload limits, return the reason when denied.
+    pub async fn check_upload(
+        fid: &i64,
+        mid: &i64,
+        msg: &NetworkMessage,
+        is_encrypted: bool,
+    ) -> Result<Option<String>> {
+        // reference must be uploaded first.
+        let attachment = match msg {
+            NetworkMessage::ImageRef(a) => Some(a),
+            NetworkMessage::FileRef(_, a) => Some(a),
+            NetworkMessage::RecordRef(a, _) => Some(a),
+            _ => None,
+        };
+        if let Some(a) = attachment {
+            if is_encrypted {
+                return Ok(Some(
+                    "attachment not supported in encrypted group.".to_owned(),
+                ));
+            }
+            if Blob::size(fid, &hash_hex(&a.hash)?).await?.is_none() {
+                return Ok(Some("attachment missing, upload it first.".to_owned()));
+            }
+        }
+
+        let size = upload_size(msg, is_encrypted);
+        if size == 0 {
+            return Ok(None);
+        }
+
+        Upload::check(fid, mid, size, limits().message_size).await
+    }
+
     pub async fn from_network_message(
+        tx: &mut Tx,
         base: &PathBuf,
         gcd: &GroupId,
         fid: &i64,
-        m_id: &GroupId,
+        mid: &i64,
         msg: &NetworkMessage,
+        is_encrypted: bool,
     ) -> Result<i64> {
         let start = SystemTime::now();
         let datetime = start
@@ -454,87 +1050,160 @@ impl Message {
             .map(|s| s.as_secs())
             .unwrap_or(0) as i64; // safe for all life.
 
-        let member = Member::get(fid, m_id).await?;
+        let size = upload_size(msg, is_encrypted);
+        if size > 0 {
+            Upload::insert(tx, fid, mid, &size, &datetime).await?;
+        }
 
         // handle event.
-        let (m_type, raw) = match msg {
-            NetworkMessage::String(content) => (MessageType::String, content.to_owned()),
-            NetworkMessage::Image(bytes) => {
-                let image_name = write_image(base, &gcd, bytes).await?;
-                (MessageType::Image, image_name)
-            }
-            NetworkMessage::File(old_name, bytes) => {
-                let filename = write_file(base, &gcd, &old_name, bytes).await?;
-                (MessageType::File, filename)
-            }
-            NetworkMessage::Contact(name, rgid, addr, avatar_bytes) => {
-                write_avatar(base, gcd, &rgid, avatar_bytes).await?;
-                let tmp_name = name.replace(";", "-;");
-                let contact_values = format!("{};;{};;{}", tmp_name, rgid.to_hex(), addr.to_hex());
-                (MessageType::Contact, contact_values)
-            }
-            NetworkMessage::Emoji => {
-                // TODO
-                (MessageType::Emoji, "".to_owned())
-            }
-            NetworkMessage::Record(bytes, time) => {
-                let record_name = write_record(base, &gcd, fid, time, bytes).await?;
-                (MessageType::Record, record_name)
-            }
-            NetworkMessage::Phone => {
-                // TODO
-                (MessageType::Phone, "".to_owned())
-            }
-            NetworkMessage::Video => {
-                // TODO
-                (MessageType::Video, "".to_owned())
+        let (m_type, raw) = if is_encrypted {
+            // encrypted group's message is opaque, save it as it is.
+            let bytes = Self::encrypted_content(msg)?;
+            let hash = Blob::put(tx, base, gcd, fid, &bytes).await?;
+            (MessageType::Encrypted, hash)
+        } else {
+            match msg {
+                NetworkMessage::String(content) => (MessageType::String, content.to_owned()),
+                NetworkMessage::Image(bytes) => {
+                    let hash = Blob::put(tx, base, gcd, fid, bytes).await?;
+                    write_thumb(base, gcd, &hash, bytes).await?;
+                    (MessageType::Image, hash)
+                }
+                NetworkMessage::File(old_name, bytes) => {
+                    // file name only for show, file saved by hash.
+                    let hash = Blob::put(tx, base, gcd, fid, bytes).await?;
+                    (MessageType::File, format!("{}-{}", hash, old_name))
+                }
+                NetworkMessage::Contact(name, rgid, addr, avatar_bytes) => {
+                    // card's avatar is message's blob, not the member's avatar.
+                    let hash = if avatar_bytes.is_empty() {
+                        "".to_owned()
+                    } else {
+                        Blob::put(tx, base, gcd, fid, avatar_bytes).await?
+                    };
+                    let tmp_name = name.replace(";", "-;");
+                    let contact_values = format!(
+                        "{};;{};;{};;{}",
+                        tmp_name,
+                        rgid.to_hex(),
+                        addr.to_hex(),
+                        hash
+                    );
+                    (MessageType::Contact, contact_values)
+                }
+                NetworkMessage::Emoji => {
+                    // TODO
+                    (MessageType::Emoji, "".to_owned())
+                }
+                NetworkMessage::Record(bytes, time) => {
+                    let hash = Blob::put(tx, base, gcd, fid, bytes).await?;
+                    (MessageType::Record, format!("{}-{}", time, hash))
+                }
+                NetworkMessage::Phone => {
+                    // TODO
+                    (MessageType::Phone, "".to_owned())
+                }
+                NetworkMessage::Video => {
+                    // TODO
+                    (MessageType::Video, "".to_owned())
+                }
+                NetworkMessage::Invite(content) => (MessageType::Invite, content.to_owned()),
+                NetworkMessage::ImageRef(a) => {
+                    let hash = Blob::refer(tx, fid, &a.hash).await?;
+                    // streamed upload has no thumbnail.
+                    let _ = read_thumb(base, gcd, &hash).await;
+                    (MessageType::Image, hash)
+                }
+                NetworkMessage::FileRef(old_name, a) => {
+                    let hash = Blob::refer(tx, fid, &a.hash).await?;
+                    (MessageType::File, format!("{}-{}", hash, old_name))
+                }
+                NetworkMessage::RecordRef(a, time) => {
+                    let hash = Blob::refer(tx, fid, &a.hash).await?;
+                    (MessageType::Record, format!("{}-{}", time, hash))
+                }
+                NetworkMessage::None => (MessageType::String, "".to_owned()),
             }
-            NetworkMessage::Invite(content) => (MessageType::Invite, content.to_owned()),
-            NetworkMessage::None => (MessageType::String, "".to_owned()),
         };
 
         let rec = sqlx::query!(
             "INSERT INTO messages (fid, mid, m_type, m_content, datetime) VALUES ($1, $2, $3, $4, $5) RETURNING id",
             fid,
-            member.id,
+            mid,
             m_type.to_i16(),
             raw,
             datetime,
-        ).fetch_one(get_pool()?).await.map_err(|_| anyhow!("database failure."))?;
+        ).fetch_one(&mut *tx).await.map_err(|_| anyhow!("database failure."))?;
 
         Ok(rec.id)
     }
 
-    async fn to_network_message(self, base: &PathBuf, gcd: &GroupId) -> Result<NetworkMessage> {
+    /// lazy: attachments only send the reference, client fetch bytes when need.
+    async fn to_network_message(
+        self,
+        base: &PathBuf,
+        gcd: &GroupId,
+        lazy: bool,
+    ) -> Result<NetworkMessage> {
+        if lazy {
+            match self.m_type {
+                MessageType::Image => {
+                    let a = attachment(base, gcd, &self.fid, &self.m_content, None).await?;
+                    return Ok(NetworkMessage::ImageRef(a));
+                }
+                MessageType::File => {
+                    if let Some((hash, name)) = self.m_content.split_once('-') {
+                        let mime = "application/octet-stream";
+                        let a = attachment(base, gcd, &self.fid, hash, Some(mime)).await?;
+                        return Ok(NetworkMessage::FileRef(name.to_owned(), a));
+                    }
+                }
+                MessageType::Record => {
+                    if let Some((time, hash)) = parse_record(&self.m_content) {
+                        let a = attachment(base, gcd, &self.fid, hash, Some("audio/mp4")).await?;
+                        return Ok(NetworkMessage::RecordRef(a, time));
+                    }
+                }
+                _ => {}
+            }
+        }
+
         match self.m_type {
             MessageType::String => Ok(NetworkMessage::String(self.m_content)),
             MessageType::Image => {
-                let bytes = read_image(base, gcd, &self.m_content).await?;
+                let bytes = read_blob(base, gcd, &self.m_content).await?;
                 Ok(NetworkMessage::Image(bytes))
             }
             MessageType::File => {
-                let bytes = read_file(base, gcd, &self.m_content).await?;
-                Ok(NetworkMessage::File(self.m_content, bytes))
-            }
-            MessageType::Contact => {
-                let v: Vec<&str> = self.m_content.split(";;").collect();
-                if v.len() != 3 {
-                    Ok(NetworkMessage::None)
+                if let Some((hash, name)) = self.m_content.split_once('-') {
+                    let bytes = read_blob(base, gcd, hash).await?;
+                    Ok(NetworkMessage::File(name.to_owned(), bytes))
                 } else {
-                    let cname = v[0].to_owned();
-                    let cgid = GroupId::from_hex(v[1])?;
-                    let caddr = PeerAddr::from_hex(v[2])?;
-                    let avatar_bytes = read_avatar(base, gcd, &cgid).await?;
-                    let avatar = vec![];
-                    Ok(NetworkMessage::Contact(cname, cgid, caddr, avatar))
+                    Ok(NetworkMessage::File(self.m_content, vec![]))
                 }
             }
+            MessageType::Contact => {
+                // name maybe has ';', so split from right.
+                // old card has no avatar's hash, its avatar saved as the member's.
+                let v: Vec<&str> = self.m_content.rsplitn(4, ";;").collect();
+                let (v, hash) = match v.len() {
+                    4 => (&v[1..], Some(v[0])),
+                    3 => (&v[..], None),
+                    _ => return Ok(NetworkMessage::None),
+                };
+                let cname = v[2].replace("-;", ";");
+                let cgid = GroupId::from_hex(v[1])?;
+                let caddr = PeerAddr::from_hex(v[0])?;
+                let avatar = if let Some(hash) = hash {
+                    read_blob(base, gcd, hash).await?
+                } else {
+                    Avatar::read(base, gcd, &self.fid, &cgid).await?
+                };
+                Ok(NetworkMessage::Contact(cname, cgid, caddr, avatar))
+            }
             MessageType::Record => {
-                let (bytes, time) = if let Some(i) = self.m_content.find('-') {
-                    let time = self.m_content[0..i].parse().unwrap_or(0);
-                    let bytes = read_record(base, gcd, &self.m_content[i + 1..]).await?;
-                    let bytes = vec![];
-                    (bytes, time)
+                let (bytes, time) = if let Some((time, hash)) = parse_record(&self.m_content) {
+                    (read_blob(base, gcd, hash).await?, time)
                 } else {
                     (vec![], 0)
                 };
@@ -544,26 +1213,58 @@ impl Message {
             MessageType::Phone => Ok(NetworkMessage::Phone),
             MessageType::Video => Ok(NetworkMessage::Video),
             MessageType::Invite => Ok(NetworkMessage::Invite(self.m_content)),
+            MessageType::Encrypted => {
+                let bytes = read_blob(base, gcd, &self.m_content).await?;
+                if bytes.is_empty() {
+                    // file lost, history keeps going.
+                    return Ok(NetworkMessage::None);
+                }
+                Self::from_encrypted_content(&bytes)
+            }
         }
     }
 
-    pub async fn get_id(id: &i64) -> Result<Message> {
+    /// encrypted message's stored bytes, the payload is ciphertext from client.
+    fn encrypted_content(msg: &NetworkMessage) -> Result<Vec<u8>> {
+        bincode::serialize(msg).map_err(|_| anyhow!("serialize message error."))
+    }
+
+    fn from_encrypted_content(bytes: &[u8]) -> Result<NetworkMessage> {
+        bincode::deserialize(bytes).map_err(|_| anyhow!("deserialize message error."))
+    }
+
+    /// if the blob is an image message in the group.
+    pub async fn is_image(fid: &i64, hash: &str) -> Result<bool> {
+        sqlx::query!(
+            "SELECT id FROM messages WHERE fid = $1 AND m_type = $2 AND m_content = $3 LIMIT 1",
+            fid,
+            MessageType::Image.to_i16(),
+            hash
+        )
+        .fetch_optional(get_pool()?)
+        .await
+        .map_err(|_| anyhow!("database failure."))
+        .map(|v| v.is_some())
+    }
+
+    /// None when it is not found.
+    pub async fn get_id(id: &i64) -> Result<Option<Message>> {
         let rec = sqlx::query!(
             "SELECT id, fid, mid, m_type, m_content, datetime FROM messages WHERE id = $1",
             id,
         )
-        .fetch_one(get_pool()?)
+        .fetch_optional(get_pool()?)
         .await
         .map_err(|_| anyhow!("database failure."))?;
 
-        Ok(Message {
+        Ok(rec.map(|rec| Message {
             id: rec.id,
             fid: rec.fid,
             mid: rec.mid,
             m_type: MessageType::from_i16(rec.m_type),
             m_content: rec.m_content,
             datetime: rec.datetime,
-        })
+        }))
     }
 }
 
@@ -573,6 +1274,7 @@ pub(crate) enum ConsensusType {
     GroupManagerAdd,
     GroupManagerDel,
     GroupClose,
+    GroupReopen,
     MemberInfo,
     MemberJoin,
     MemberLeave,
@@ -593,6 +1295,7 @@ impl ConsensusType {
             ConsensusType::MemberJoin => 7,
             ConsensusType::MemberLeave => 8,
             ConsensusType::MessageCreate => 9,
+            ConsensusType::GroupReopen => 10,
         }
     }
 
@@ -607,6 +1310,7 @@ impl ConsensusType {
             7 => ConsensusType::MemberJoin,
             8 => ConsensusType::MemberLeave,
             9 => ConsensusType::MessageCreate,
+            10 => ConsensusType::GroupReopen,
             _ => ConsensusType::None,
         }
     }
@@ -633,6 +1337,7 @@ impl Consensus {
         fid: &i64,
         from: &i64,
         to: &i64,
+        lazy: bool,
     ) -> Result<Vec<PackedEvent>> {
         let recs =
             sqlx::query!("SELECT id, fid, height, ctype, cid FROM consensus WHERE fid = $1 AND height BETWEEN $2 AND $3", fid, from, to)
@@ -640,88 +1345,750 @@ impl Consensus {
             .await
             .map_err(|_| anyhow!("database failure."))?;
 
+        let mut heights: HashMap<i64, (i16, i64)> = HashMap::new();
+        for res in recs {
+            heights.insert(res.height, (res.ctype, res.cid));
+        }
+
+        // large group only send member avatar's hash, client fetch it when need.
+        let avatar_hash = Member::count(fid).await? > AVATAR_HASH_LIMIT;
+
+        // every height has a packed event, missing one is None.
         let mut packed = vec![];
+        for height in *from..=*to {
+            let event = if let Some((ctype, cid)) = heights.remove(&height) {
+                Self::pack_one(
+                    base,
+                    gcd,
+                    fid,
+                    ConsensusType::from_i16(ctype),
+                    &cid,
+                    avatar_hash,
+                    lazy,
+                )
+                .await?
+                // referenced row is deleted, it is tombstone.
+                .unwrap_or(PackedEvent::None)
+            } else {
+                PackedEvent::None
+            };
+            packed.push(event);
+        }
 
-        for res in recs {
-            match ConsensusType::from_i16(res.ctype) {
-                ConsensusType::GroupInfo => {
-                    //
-                }
-                ConsensusType::GroupTransfer => {
-                    //
-                }
-                ConsensusType::GroupManagerAdd => {
-                    //
-                }
-                ConsensusType::GroupManagerDel => {
-                    //
-                }
-                ConsensusType::GroupClose => {
-                    //
-                }
-                ConsensusType::MemberInfo => {
-                    //
-                }
-                ConsensusType::MemberJoin => {
-                    let m = Member::get_id(&res.cid).await?;
-                    // TODO load member avatar.
-                    let mavatar = vec![];
-                    packed.push(PackedEvent::MemberJoin(
-                        m.m_id, m.m_addr, m.m_name, mavatar, m.datetime,
-                    ))
-                }
-                ConsensusType::MemberLeave => {
-                    //
-                }
-                ConsensusType::MessageCreate => {
-                    let m = Message::get_id(&res.cid).await?;
-                    let datetime = m.datetime;
-                    let mem = Member::get_id(&m.mid).await?;
-                    let nmsg = m.to_network_message(base, gcd).await?;
-                    packed.push(PackedEvent::MessageCreate(mem.m_id, nmsg, datetime))
-                }
-                ConsensusType::None => {
-                    // None
+        Ok(packed)
+    }
+
+    async fn pack_one(
+        base: &PathBuf,
+        gcd: &GroupId,
+        fid: &i64,
+        ctype: ConsensusType,
+        cid: &i64,
+        avatar_hash: bool,
+        lazy: bool,
+    ) -> Result<Option<PackedEvent>> {
+        match ctype {
+            ConsensusType::GroupInfo => {
+                let v = match GroupVersion::get_id(cid).await? {
+                    Some(v) => v,
+                    None => return Ok(None),
+                };
+                // the avatar at this height, not the current one.
+                let gavatar = if v.avatar.is_empty() {
+                    vec![]
+                } else {
+                    read_blob(base, gcd, &v.avatar).await?
+                };
+                Ok(Some(PackedEvent::GroupInfo(
+                    v.g_name,
+                    v.g_bio,
+                    gavatar,
+                    v.is_need_agree,
+                )))
+            }
+            ConsensusType::GroupTransfer => {
+                let m = match Member::get_id(cid).await? {
+                    Some(m) => m,
+                    None => return Ok(None),
+                };
+                Ok(Some(PackedEvent::GroupTransfer(m.m_id)))
+            }
+            ConsensusType::GroupManagerAdd => {
+                let m = match Member::get_id(cid).await? {
+                    Some(m) => m,
+                    None => return Ok(None),
+                };
+                Ok(Some(PackedEvent::GroupManagerAdd(m.m_id)))
+            }
+            ConsensusType::GroupManagerDel => {
+                let m = match Member::get_id(cid).await? {
+                    Some(m) => m,
+                    None => return Ok(None),
+                };
+                Ok(Some(PackedEvent::GroupManagerDel(m.m_id)))
+            }
+            ConsensusType::GroupClose => Ok(Some(PackedEvent::GroupClose)),
+            ConsensusType::GroupReopen => Ok(Some(PackedEvent::GroupReopen)),
+            ConsensusType::MemberInfo => {
+                // the info at this height, not the current one.
+                let v = match MemberVersion::get_id(cid).await? {
+                    Some(v) => v,
+                    None => return Ok(None),
+                };
+                let mavatar = if v.avatar.is_empty() {
+                    vec![]
+                } else {
+                    read_blob(base, gcd, &v.avatar).await?
+                };
+                Ok(Some(PackedEvent::MemberInfo(
+                    v.m_id, v.m_addr, v.m_name, mavatar,
+                )))
+            }
+            ConsensusType::MemberJoin => {
+                let m = match Member::get_id(cid).await? {
+                    Some(m) => m,
+                    None => return Ok(None),
+                };
+                if avatar_hash {
+                    // only avatar's hash, client fetch it by MemberAvatar when need.
+                    let ahash = Avatar::hash(fid, &m.m_id).await?;
+                    return Ok(Some(PackedEvent::MemberJoinRef(
+                        m.m_id, m.m_addr, m.m_name, ahash, m.datetime,
+                    )));
                 }
+
+                let mavatar = Avatar::read(base, gcd, fid, &m.m_id).await?;
+                Ok(Some(PackedEvent::MemberJoin(
+                    m.m_id, m.m_addr, m.m_name, mavatar, m.datetime,
+                )))
+            }
+            ConsensusType::MemberLeave => {
+                let m = match Member::get_id(cid).await? {
+                    Some(m) => m,
+                    None => return Ok(None),
+                };
+                Ok(Some(PackedEvent::MemberLeave(m.m_id)))
             }
+            ConsensusType::MessageCreate => {
+                let m = match Message::get_id(cid).await? {
+                    Some(m) => m,
+                    None => return Ok(None),
+                };
+                let datetime = m.datetime;
+                let mem = match Member::get_id(&m.mid).await? {
+                    Some(mem) => mem,
+                    None => return Ok(None),
+                };
+                let nmsg = m.to_network_message(base, gcd, lazy).await?;
+                Ok(Some(PackedEvent::MessageCreate(mem.m_id, nmsg, datetime)))
+            }
+            ConsensusType::None => Ok(Some(PackedEvent::None)),
         }
+    }
 
-        Ok(packed)
+    /// height is unique in group, never overwrite it.
+    pub async fn insert(
+        tx: &mut Tx,
+        fid: &i64,
+        height: &i64,
+        cid: &i64,
+        ctype: &ConsensusType,
+    ) -> Result<()> {
+        let _ = sqlx::query!(
+            "INSERT INTO consensus ( fid, height, ctype, cid ) VALUES ( $1, $2, $3, $4 )",
+            fid,
+            height,
+            ctype.to_i16(),
+            cid
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| anyhow!("database failure."))?;
+
+        Ok(())
     }
 
-    pub async fn insert(fid: &i64, height: &i64, cid: &i64, ctype: &ConsensusType) -> Result<()> {
-        let unique_check = sqlx::query!(
-            "SELECT id from consensus WHERE fid = $1 AND height = $2",
+    /// check the group's consensus, return the missing heights.
+    pub async fn gaps(fid: &i64, height: &i64) -> Result<Vec<i64>> {
+        let recs = sqlx::query!(
+            "SELECT s.h AS height FROM generate_series(1, $2::BIGINT) AS s(h) LEFT JOIN consensus c ON c.fid = $1 AND c.height = s.h WHERE c.id IS NULL ORDER BY s.h",
             fid,
             height
         )
+        .fetch_all(get_pool()?)
+        .await
+        .map_err(|_| anyhow!("database failure."))?;
+
+        Ok(recs.into_iter().filter_map(|r| r.height).collect())
+    }
+}
+
+/// attachment's reference, image's mime is from its bytes, and has thumbnail.
+async fn attachment(
+    base: &PathBuf,
+    gcd: &GroupId,
+    fid: &i64,
+    hash: &str,
+    mime: Option<&str>,
+) -> Result<Attachment> {
+    let size = Blob::size(fid, hash).await?.unwrap_or(0) as u64;
+    let (mime, thumbnail) = if let Some(mime) = mime {
+        (mime.to_owned(), vec![])
+    } else {
+        let header = read_blob_chunk(base, gcd, hash, 0, 32).await?;
+        let thumbnail = read_thumb(base, gcd, hash).await.unwrap_or(vec![]);
+        (image_mime(&header), thumbnail)
+    };
+
+    // lost legacy file has no hash, it is an empty attachment.
+    Ok(Attachment {
+        hash: hex::decode(hash).unwrap_or(vec![]),
+        size,
+        mime,
+        thumbnail,
+    })
+}
+
+/// bytes which message will save to disk.
+fn upload_size(msg: &NetworkMessage, is_encrypted: bool) -> i64 {
+    if is_encrypted {
+        return bincode::serialized_size(msg).unwrap_or(0) as i64;
+    }
+    let size = match msg {
+        NetworkMessage::Image(bytes) => bytes.len(),
+        NetworkMessage::File(_, bytes) => bytes.len(),
+        NetworkMessage::Record(bytes, _) => bytes.len(),
+        NetworkMessage::Contact(_, _, _, avatar) => avatar.len(),
+        _ => 0,
+    };
+    size as i64
+}
+
+/// Member's uploaded bytes, for daily quota.
+pub(crate) struct Upload;
+
+impl Upload {
+    /// check the upload limits, return the reason when denied.
+    /// max: message size limit, or file size limit of streamed upload.
+    pub async fn check(fid: &i64, mid: &i64, size: i64, max: i64) -> Result<Option<String>> {
+        let limits = limits();
+        if size > max {
+            return Ok(Some(format!(
+                "too large: {} bytes, limit {} bytes.",
+                size, max
+            )));
+        }
+
+        if Blob::usage(fid).await? + size > limits.group_quota {
+            return Ok(Some(format!(
+                "group storage full: quota {} bytes.",
+                limits.group_quota
+            )));
+        }
+
+        if Self::today(fid, mid).await? + size > limits.member_daily {
+            return Ok(Some(format!(
+                "daily upload limit reached: {} bytes.",
+                limits.member_daily
+            )));
+        }
+
+        Ok(None)
+    }
+
+    pub async fn insert(
+        tx: &mut Tx,
+        fid: &i64,
+        mid: &i64,
+        size: &i64,
+        datetime: &i64,
+    ) -> Result<()> {
+        let _ = sqlx::query!(
+            "INSERT INTO uploads (fid, mid, size, datetime) VALUES ($1, $2, $3, $4)",
+            fid,
+            mid,
+            size,
+            datetime
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| anyhow!("database failure."))?;
+
+        Ok(())
+    }
+
+    /// uploaded bytes in today (UTC).
+    pub async fn today(fid: &i64, mid: &i64) -> Result<i64> {
+        let start = SystemTime::now();
+        let now = start
+            .duration_since(UNIX_EPOCH)
+            .map(|s| s.as_secs())
+            .unwrap_or(0) as i64; // safe for all life.
+        let day = now - now % 86400;
+
+        let rec = sqlx::query!(
+            "SELECT COALESCE(SUM(size), 0)::BIGINT AS total FROM uploads WHERE fid = $1 AND mid = $2 AND datetime >= $3",
+            fid,
+            mid,
+            day
+        )
+        .fetch_one(get_pool()?)
+        .await
+        .map_err(|_| anyhow!("database failure."))?;
+
+        Ok(rec.total.unwrap_or(0))
+    }
+}
+
+/// Content-addressed file, saved once in group by blake3 hash.
+/// refs is how many messages and avatars use it.
+pub(crate) struct Blob;
+
+impl Blob {
+    /// save bytes and add a reference, return the hash.
+    pub async fn put(
+        tx: &mut Tx,
+        base: &PathBuf,
+        gcd: &GroupId,
+        fid: &i64,
+        bytes: &[u8],
+    ) -> Result<String> {
+        let start = SystemTime::now();
+        let datetime = start
+            .duration_since(UNIX_EPOCH)
+            .map(|s| s.as_secs())
+            .unwrap_or(0) as i64; // safe for all life.
+
+        let hash = write_blob(base, gcd, bytes).await?;
+        let _ = sqlx::query!(
+            "INSERT INTO blobs (fid, hash, size, refs, datetime) VALUES ($1, $2, $3, 1, $4) ON CONFLICT (fid, hash) DO UPDATE SET refs = blobs.refs + 1",
+            fid,
+            hash,
+            bytes.len() as i64,
+            datetime
+        ).execute(&mut *tx).await.map_err(|_| anyhow!("database failure."))?;
+
+        Ok(hash)
+    }
+
+    /// group's used bytes.
+    pub async fn usage(fid: &i64) -> Result<i64> {
+        let rec = sqlx::query!(
+            "SELECT COALESCE(SUM(size), 0)::BIGINT AS total FROM blobs WHERE fid = $1",
+            fid
+        )
+        .fetch_one(get_pool()?)
+        .await
+        .map_err(|_| anyhow!("database failure."))?;
+
+        Ok(rec.total.unwrap_or(0))
+    }
+
+    /// all groups' used bytes, (group id, bytes, blobs number).
+    pub async fn all_usage() -> Result<Vec<(GroupId, i64, i64)>> {
+        let recs = sqlx::query!(
+            "SELECT groups.g_id, COALESCE(SUM(blobs.size), 0)::BIGINT AS total, COUNT(blobs.id) AS count FROM groups LEFT JOIN blobs ON blobs.fid = groups.id WHERE groups.is_deleted = false GROUP BY groups.id ORDER BY groups.id",
+        )
+        .fetch_all(get_pool()?)
+        .await
+        .map_err(|_| anyhow!("database failure."))?;
+
+        let mut usages = vec![];
+        for rec in recs {
+            usages.push((
+                GroupId::from_hex(rec.g_id).unwrap_or(GroupId::default()),
+                rec.total.unwrap_or(0),
+                rec.count.unwrap_or(0),
+            ));
+        }
+        Ok(usages)
+    }
+
+    /// add a streamed upload which no message used yet.
+    /// it is kept `UPLOAD_KEEP` seconds, waiting for the message.
+    pub async fn add(tx: &mut Tx, fid: &i64, hash: &str, size: &i64) -> Result<()> {
+        let start = SystemTime::now();
+        let datetime = start
+            .duration_since(UNIX_EPOCH)
+            .map(|s| s.as_secs())
+            .unwrap_or(0) as i64; // safe for all life.
+
+        let _ = sqlx::query!(
+            "INSERT INTO blobs (fid, hash, size, refs, datetime) VALUES ($1, $2, $3, 0, $4) ON CONFLICT (fid, hash) DO UPDATE SET datetime = $4",
+            fid,
+            hash,
+            size,
+            datetime
+        ).execute(&mut *tx).await.map_err(|_| anyhow!("database failure."))?;
+
+        Ok(())
+    }
+
+    /// add a reference to the uploaded blob, return the hash hex.
+    pub async fn refer(tx: &mut Tx, fid: &i64, hash: &[u8]) -> Result<String> {
+        let hash = hash_hex(hash)?;
+        let rec = sqlx::query!(
+            "UPDATE blobs SET refs = refs + 1 WHERE fid = $1 AND hash = $2 RETURNING id",
+            fid,
+            hash
+        )
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(|_| anyhow!("database failure."))?;
+
+        if rec.is_some() {
+            Ok(hash)
+        } else {
+            Err(anyhow!("attachment missing."))
+        }
+    }
+
+    /// the blob's size, None if not in group.
+    pub async fn size(fid: &i64, hash: &str) -> Result<Option<i64>> {
+        let rec = sqlx::query!(
+            "SELECT size FROM blobs WHERE fid = $1 AND hash = $2",
+            fid,
+            hash
+        )
         .fetch_optional(get_pool()?)
         .await
         .map_err(|_| anyhow!("database failure."))?;
 
-        if let Some(rec) = unique_check {
+        Ok(rec.map(|r| r.size))
+    }
+
+    /// remove a reference, the file deleted by `collect` after commit.
+    pub async fn release(tx: &mut Tx, fid: &i64, hash: &str) -> Result<()> {
+        let _ = sqlx::query!(
+            "UPDATE blobs SET refs = refs - 1 WHERE fid = $1 AND hash = $2",
+            fid,
+            hash
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| anyhow!("database failure."))?;
+
+        Ok(())
+    }
+
+    /// delete the blobs which no one used.
+    pub async fn collect(base: &PathBuf, gcd: &GroupId, fid: &i64) -> Result<()> {
+        let start = SystemTime::now();
+        let datetime = start
+            .duration_since(UNIX_EPOCH)
+            .map(|s| s.as_secs())
+            .unwrap_or(0) as i64; // safe for all life.
+
+        let recs = sqlx::query!(
+            "DELETE FROM blobs WHERE fid = $1 AND refs <= 0 AND datetime < $2 RETURNING hash",
+            fid,
+            datetime - UPLOAD_KEEP
+        )
+        .fetch_all(get_pool()?)
+        .await
+        .map_err(|_| anyhow!("database failure."))?;
+
+        for rec in recs {
+            delete_blob(base, gcd, rec.hash.trim()).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Avatar of group/member/contact in group, the avatar is a blob.
+pub(crate) struct Avatar;
+
+impl Avatar {
+    /// change the avatar, empty bytes will keep the old one.
+    pub async fn write(
+        tx: &mut Tx,
+        base: &PathBuf,
+        gcd: &GroupId,
+        fid: &i64,
+        rid: &GroupId,
+        bytes: &[u8],
+    ) -> Result<()> {
+        if bytes.is_empty() {
+            return Ok(());
+        }
+
+        let old = Self::get(tx, fid, rid).await?;
+        let hash = blake3::hash(bytes).to_hex().to_string();
+        if old.as_ref() == Some(&hash) {
+            return Ok(());
+        }
+
+        let hash = Blob::put(tx, base, gcd, fid, bytes).await?;
+        let _ = sqlx::query!(
+            "INSERT INTO avatars (fid, r_id, hash) VALUES ($1, $2, $3) ON CONFLICT (fid, r_id) DO UPDATE SET hash = $3",
+            fid,
+            rid.to_hex(),
+            hash
+        ).execute(&mut *tx).await.map_err(|_| anyhow!("database failure."))?;
+
+        if let Some(old) = old {
+            Blob::release(tx, fid, &old).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn delete(tx: &mut Tx, fid: &i64, rid: &GroupId) -> Result<()> {
+        if let Some(old) = Self::get(tx, fid, rid).await? {
             let _ = sqlx::query!(
-                "UPDATE consensus SET ctype = $1, cid = $2 WHERE id = $3",
-                ctype.to_i16(),
-                cid,
-                rec.id
+                "DELETE FROM avatars WHERE fid = $1 AND r_id = $2",
+                fid,
+                rid.to_hex()
             )
-            .execute(get_pool()?)
+            .execute(&mut *tx)
             .await
             .map_err(|_| anyhow!("database failure."))?;
+
+            Blob::release(tx, fid, &old).await?;
+        }
+        Ok(())
+    }
+
+    /// avatar's blob hash in transaction.
+    pub async fn get(tx: &mut Tx, fid: &i64, rid: &GroupId) -> Result<Option<String>> {
+        let rec = sqlx::query!(
+            "SELECT hash FROM avatars WHERE fid = $1 AND r_id = $2",
+            fid,
+            rid.to_hex()
+        )
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(|_| anyhow!("database failure."))?;
+
+        Ok(rec.map(|r| r.hash.trim().to_owned()))
+    }
+
+    /// avatar's blake3 hash, empty when no avatar.
+    pub async fn hash(fid: &i64, rid: &GroupId) -> Result<Vec<u8>> {
+        let rec = sqlx::query!(
+            "SELECT hash FROM avatars WHERE fid = $1 AND r_id = $2",
+            fid,
+            rid.to_hex()
+        )
+        .fetch_optional(get_pool()?)
+        .await
+        .map_err(|_| anyhow!("database failure."))?;
+
+        if let Some(rec) = rec {
+            hex::decode(rec.hash.trim()).map_err(|_| anyhow!("avatar hash invalid."))
         } else {
+            Ok(vec![])
+        }
+    }
+
+    /// avatar's bytes, empty when no avatar.
+    pub async fn read(base: &PathBuf, gcd: &GroupId, fid: &i64, rid: &GroupId) -> Result<Vec<u8>> {
+        let rec = sqlx::query!(
+            "SELECT hash FROM avatars WHERE fid = $1 AND r_id = $2",
+            fid,
+            rid.to_hex()
+        )
+        .fetch_optional(get_pool()?)
+        .await
+        .map_err(|_| anyhow!("database failure."))?;
+
+        if let Some(rec) = rec {
+            read_blob(base, gcd, rec.hash.trim()).await
+        } else {
+            Ok(vec![])
+        }
+    }
+}
+
+/// move the files which saved before blob store into it.
+/// old file removed after migrated, so it is safe to run every start.
+pub(crate) async fn migrate_legacy_files(base: &PathBuf) -> Result<()> {
+    for group in GroupChat::all().await? {
+        let (gcd, fid) = (group.g_id, group.id);
+        init_local_files(base, &gcd).await?;
+        let mut olds = vec![];
+        let mut tx = begin().await?;
+
+        let recs = sqlx::query!(
+            "SELECT id, m_type, m_content FROM messages WHERE fid = $1 AND m_type IN ($2, $3, $4, $5)",
+            fid,
+            MessageType::Image.to_i16(),
+            MessageType::File.to_i16(),
+            MessageType::Record.to_i16(),
+            MessageType::Encrypted.to_i16(),
+        ).fetch_all(&mut tx).await.map_err(|_| anyhow!("database failure."))?;
+
+        for rec in recs {
+            let content = rec.m_content;
+            let (dir, name) = match MessageType::from_i16(rec.m_type) {
+                MessageType::Image if !is_blob_name(&content) => {
+                    (LEGACY_IMAGE_DIR, content.as_str())
+                }
+                MessageType::File | MessageType::Encrypted => (LEGACY_FILES_DIR, content.as_str()),
+                MessageType::Record => match parse_record(&content) {
+                    Some((_, name)) if !is_blob_name(name) => (LEGACY_RECORD_DIR, name),
+                    _ => continue,
+                },
+                _ => continue,
+            };
+            let (path, bytes) = if let Some(v) = read_legacy(base, &gcd, dir, name).await? {
+                v
+            } else {
+                continue;
+            };
+
+            let hash = Blob::put(&mut tx, base, &gcd, &fid, &bytes).await?;
+            let new_content = match MessageType::from_i16(rec.m_type) {
+                MessageType::File => format!("{}-{}", hash, name),
+                MessageType::Record => content.replacen(name, &hash, 1),
+                _ => hash.clone(),
+            };
+            if let MessageType::Image = MessageType::from_i16(rec.m_type) {
+                let _ = write_thumb(base, &gcd, &hash, &bytes).await;
+            }
+
             let _ = sqlx::query!(
-                "INSERT INTO consensus ( fid, height, ctype, cid ) VALUES ( $1, $2, $3, $4 )",
-                fid,
-                height,
-                ctype.to_i16(),
-                cid
+                "UPDATE messages SET m_content = $1 WHERE id = $2",
+                new_content,
+                rec.id
             )
-            .execute(get_pool()?)
+            .execute(&mut tx)
             .await
             .map_err(|_| anyhow!("database failure."))?;
+            olds.push(path);
         }
 
-        Ok(())
+        for (rid, path) in list_legacy_avatars(base, &gcd).await? {
+            let bytes = tokio::fs::read(&path).await?;
+            Avatar::write(&mut tx, base, &gcd, &fid, &rid, &bytes).await?;
+            olds.push(path);
+        }
+
+        tx.commit()
+            .await
+            .map_err(|_| anyhow!("database failure."))?;
+
+        // same file maybe used by many messages, remove after all migrated.
+        olds.sort();
+        olds.dedup();
+        if !olds.is_empty() {
+            println!("group {} migrated {} files.", gcd.to_hex(), olds.len());
+        }
+        for path in olds {
+            let _ = tokio::fs::remove_file(path).await;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::init_local_files;
+    use aes_gcm::aead::{Aead, NewAead};
+    use aes_gcm::{Aes256Gcm, Key, Nonce};
+
+    fn encrypted_group(key_hash: Vec<u8>) -> GroupChat {
+        GroupChat::new(
+            GroupId([2u8; 32]),
+            GroupId([1u8; 32]),
+            GroupType::Encrypted,
+            "".to_owned(),
+            hex::encode(b"encrypted info"),
+            false,
+            key_hash,
+        )
+    }
+
+    #[test]
+    fn encrypted_group_check_key() {
+        let key_hash = blake3::hash(&[7u8; 32]).as_bytes().to_vec();
+        let group = encrypted_group(key_hash.clone());
+
+        assert!(group.check_key(&Some(key_hash)));
+        assert!(!group.check_key(&Some(blake3::hash(&[8u8; 32]).as_bytes().to_vec())));
+        assert!(!group.check_key(&Some(vec![])));
+        assert!(!group.check_key(&None));
+
+        // group created without key hash, no one can join.
+        assert!(!encrypted_group(vec![]).check_key(&Some(vec![])));
+    }
+
+    #[test]
+    fn common_group_no_key() {
+        let mut group = encrypted_group(vec![]);
+        group.g_type = GroupType::Open;
+        assert!(group.check_key(&None));
+    }
+
+    #[tokio::test]
+    async fn encrypted_message_stored_opaque() {
+        // client encrypted the image, server cannot read it.
+        let cipher = Aes256Gcm::new(Key::from_slice(&[7u8; 32]));
+        let nonce = Nonce::from_slice(&[1u8; 12]);
+        let plain = b"meet at the old bridge";
+        let ciphertext = cipher.encrypt(nonce, &plain[..]).unwrap();
+        let msg = NetworkMessage::Image(ciphertext);
+
+        let base = std::env::temp_dir().join(format!("dao-encrypted-{}", std::process::id()));
+        let gcd = GroupId([1u8; 32]);
+        init_local_files(&base, &gcd).await.unwrap();
+
+        // whole message is counted to quota, not only the image.
+        let bytes = Message::encrypted_content(&msg).unwrap();
+        assert_eq!(upload_size(&msg, true), bytes.len() as i64);
+
+        // saved as it is, no thumbnail and no attachment reference.
+        let hash = write_blob(&base, &gcd, &bytes).await.unwrap();
+        let stored = read_blob(&base, &gcd, &hash).await.unwrap();
+        assert!(!stored.windows(plain.len()).any(|w| w == &plain[..]));
+        let message = Message {
+            id: 1,
+            fid: 1,
+            mid: 1,
+            m_type: MessageType::Encrypted,
+            m_content: hash,
+            datetime: 0,
+        };
+        let synced = message.to_network_message(&base, &gcd, true).await.unwrap();
+        let _ = tokio::fs::remove_dir_all(&base).await;
+
+        assert_eq!(bincode::serialize(&synced).unwrap(), bytes);
+    }
+
+    #[tokio::test]
+    async fn legacy_and_contact_messages_read() {
+        let base = std::env::temp_dir().join(format!("dao-legacy-{}", std::process::id()));
+        let gcd = GroupId([1u8; 32]);
+        init_local_files(&base, &gcd).await.unwrap();
+        let message = |m_type, m_content: String| Message {
+            id: 1,
+            fid: 1,
+            mid: 1,
+            m_type,
+            m_content,
+            datetime: 0,
+        };
+
+        // legacy file missing when migrate, history keeps going.
+        let old = message(MessageType::Image, "old.png".to_owned());
+        let image = old.to_network_message(&base, &gcd, false).await.unwrap();
+        let old = message(MessageType::Encrypted, "old.bin".to_owned());
+        let encrypted = old.to_network_message(&base, &gcd, false).await.unwrap();
+
+        // card's avatar is referenced by hash.
+        let (cgid, caddr) = (GroupId([2u8; 32]), PeerAddr([2u8; 32]));
+        let hash = write_blob(&base, &gcd, b"avatar").await.unwrap();
+        let content = format!("a-;b;;{};;{};;{}", cgid.to_hex(), caddr.to_hex(), hash);
+        let card = message(MessageType::Contact, content);
+        let contact = card.to_network_message(&base, &gcd, false).await.unwrap();
+        let _ = tokio::fs::remove_dir_all(&base).await;
+
+        assert!(matches!(image, NetworkMessage::Image(bytes) if bytes.is_empty()));
+        assert!(matches!(encrypted, NetworkMessage::None));
+        assert!(
+            matches!(contact, NetworkMessage::Contact(name, g, a, avatar)
+            if name == "a;b" && g == cgid && a == caddr && avatar == b"avatar")
+        );
     }
 }
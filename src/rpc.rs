@@ -6,15 +6,17 @@ use tdn::types::{
 };
 use tokio::sync::RwLock;
 
-use crate::layer::Layer;
 use crate::manager::Manager;
+use crate::models::Blob;
+use crate::shard::Shards;
+use crate::storage::limits;
 
 pub(crate) struct RpcState {
-    pub layer: Arc<RwLock<Layer>>,
+    pub shards: Arc<RwLock<Shards>>,
 }
 
-pub(crate) fn new_rpc_handler(addr: PeerAddr, layer: Arc<RwLock<Layer>>) -> RpcHandler<RpcState> {
-    let mut handler = RpcHandler::new(RpcState { layer });
+pub(crate) fn new_rpc_handler(addr: PeerAddr, shards: Arc<RwLock<Shards>>) -> RpcHandler<RpcState> {
+    let mut handler = RpcHandler::new(RpcState { shards });
 
     handler.add_method("echo", |_, params, _| async move {
         Ok(HandleResult::rpc(json!(params)))
@@ -65,5 +67,48 @@ pub(crate) fn new_rpc_handler(addr: PeerAddr, layer: Arc<RwLock<Layer>>) -> RpcH
         },
     );
 
+    handler.add_method(
+        "reopen-group",
+        |params: Vec<RpcParam>, state: Arc<RpcState>| async move {
+            let gid = GroupId::from_hex(params[0].as_str().ok_or(RpcError::ParseError)?)?;
+
+            let shard = state.shards.read().await.get(&gid)?;
+            shard
+                .call(move |layer| Box::pin(async move { layer.reopen_group(&gid).await }))
+                .await?;
+
+            Ok(HandleResult::rpc(json!(params)))
+        },
+    );
+
+    handler.add_method(
+        "group-usage",
+        |_params: Vec<RpcParam>, _state: Arc<RpcState>| async move {
+            let quota = limits().group_quota;
+            let mut vecs = vec![];
+            for (gid, size, count) in Blob::all_usage().await? {
+                vecs.push([json!(gid.to_hex()), json!(size), json!(count), json!(quota)]);
+            }
+            Ok(HandleResult::rpc(json!(vecs)))
+        },
+    );
+
+    handler.add_method(
+        "check-consensus",
+        |_params: Vec<RpcParam>, state: Arc<RpcState>| async move {
+            let shards = state.shards.read().await.all();
+            let mut gaps = vec![];
+            for shard in shards {
+                gaps.extend(shard.call(|layer| Box::pin(layer.check())).await?);
+            }
+            let gaps: Vec<_> = gaps
+                .iter()
+                .map(|(gid, heights)| json!([gid.to_hex(), heights]))
+                .collect();
+
+            Ok(HandleResult::rpc(json!(gaps)))
+        },
+    );
+
     handler
 }
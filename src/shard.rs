@@ -0,0 +1,562 @@
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Duration;
+use tdn::prelude::SendMessage;
+use tdn::types::{
+    group::GroupId,
+    message::{RecvType, SendType},
+    primitive::{HandleResult, PeerAddr, Result},
+};
+use tokio::sync::{
+    mpsc::{channel, error::TrySendError, Receiver, Sender},
+    oneshot,
+};
+
+use group_chat_types::{Event, GroupInfo, LayerConnect, LayerEvent, LayerStream};
+
+use crate::layer::{add_layer, Layer};
+use crate::transfer::token_shard;
+
+/// max waiting jobs of a shard, more messages are rejected when the shard is busy.
+const SHARD_QUEUE: usize = 1024;
+
+/// method call on the shard's layer, run in the shard's task.
+type Call =
+    Box<dyn for<'a> FnOnce(&'a mut Layer) -> Pin<Box<dyn Future<Output = ()> + Send + 'a>> + Send>;
+
+enum Job {
+    /// message from network, params: sender, message.
+    Handle(GroupId, RecvType),
+    /// device sent message to other group, it is alive.
+    Touch(PeerAddr),
+    /// offline the idle devices.
+    Sweep(Duration),
+    /// network lost, all devices are offline.
+    NetworkLost,
+    /// call the layer's method, like RPC.
+    Call(Call),
+}
+
+/// the task which own a layer, handle its jobs in order.
+#[derive(Clone)]
+pub(crate) struct Shard(Sender<Job>);
+
+impl Shard {
+    /// retire: the shard without group reports (id, handled messages), it can be removed.
+    fn spawn(
+        id: u32,
+        mut layer: Layer,
+        sender: Sender<SendMessage>,
+        retire: Sender<(u32, u64)>,
+    ) -> Shard {
+        let (tx, mut rx) = channel(SHARD_QUEUE);
+        tokio::spawn(async move {
+            let mut handled = 0;
+            while let Some(job) = rx.recv().await {
+                match job {
+                    Job::Handle(gid, msg) => {
+                        handled += 1;
+                        if let Ok(results) = layer.handle(gid, msg).await {
+                            crate::handle(results, 0, &sender).await;
+                        }
+                        // creation failed, new shard has no group.
+                        if id != 0 && layer.gids().is_empty() {
+                            let _ = retire.try_send((id, handled));
+                        }
+                    }
+                    Job::Touch(addr) => layer.touch(&addr),
+                    Job::Sweep(timeout) => {
+                        if let Ok(results) = layer.sweep(timeout) {
+                            crate::handle(results, 0, &sender).await;
+                        }
+                    }
+                    Job::NetworkLost => layer.network_lost(),
+                    Job::Call(call) => call(&mut layer).await,
+                }
+            }
+        });
+        Shard(tx)
+    }
+
+    /// the job can be dropped when the queue is full.
+    fn send(&self, job: Job) {
+        if self.0.try_send(job).is_err() {
+            println!("shard busy, job dropped.");
+        }
+    }
+
+    /// the job which never dropped, wait the queue when it is full.
+    fn send_wait(&self, job: Job) {
+        if let Err(TrySendError::Full(job)) = self.0.try_send(job) {
+            let tx = self.0.clone();
+            tokio::spawn(async move {
+                let _ = tx.send(job).await;
+            });
+        }
+    }
+
+    /// call the layer's method in the shard, wait the result.
+    pub(crate) async fn call<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: for<'a> FnOnce(&'a mut Layer) -> Pin<Box<dyn Future<Output = Result<T>> + Send + 'a>>
+            + Send
+            + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        let job = Job::Call(boxed(move |layer| {
+            Box::pin(async move {
+                let _ = tx.send(f(layer).await);
+            })
+        }));
+        // call waits the queue, never dropped.
+        self.0
+            .send(job)
+            .await
+            .map_err(|_| anyhow!("shard closed."))?;
+        rx.await.map_err(|_| anyhow!("shard closed."))?
+    }
+}
+
+fn boxed<F>(f: F) -> Call
+where
+    F: for<'a> FnOnce(&'a mut Layer) -> Pin<Box<dyn Future<Output = ()> + Send + 'a>>
+        + Send
+        + 'static,
+{
+    Box::new(f)
+}
+
+/// groups sharded to tasks, every group has its own layer.
+/// messages of one group handled in order, different groups run concurrently.
+pub(crate) struct Shards {
+    /// empty layer, new shards make from it.
+    layer: Layer,
+    /// sender to TDN, shards send their results directly.
+    sender: Sender<SendMessage>,
+    /// group to its shard id.
+    groups: HashMap<GroupId, u32>,
+    /// shard id to (shard, sent messages), 0 is the lobby which handle the messages without group.
+    shards: HashMap<u32, (Shard, u64)>,
+    /// shards without group, created for the group which creation failed.
+    retire: (Sender<(u32, u64)>, Receiver<(u32, u64)>),
+    /// devices index, address to shards which it sent messages.
+    addrs: HashMap<PeerAddr, HashSet<u32>>,
+    /// last shard id.
+    next_id: u32,
+}
+
+impl Shards {
+    pub(crate) fn new(mut layer: Layer, sender: Sender<SendMessage>) -> Shards {
+        let mut shards = Shards {
+            layer: layer.shard(0),
+            sender,
+            groups: HashMap::new(),
+            shards: HashMap::new(),
+            retire: channel(SHARD_QUEUE),
+            addrs: HashMap::new(),
+            next_id: 0,
+        };
+        shards.spawn(0, layer.shard(0));
+
+        for gid in layer.gids() {
+            let id = shards.next_id();
+            shards.spawn(id, layer.split(&gid, id));
+            shards.groups.insert(gid, id);
+        }
+
+        shards
+    }
+
+    fn spawn(&mut self, id: u32, layer: Layer) {
+        let retire = self.retire.0.clone();
+        let shard = Shard::spawn(id, layer, self.sender.clone(), retire);
+        self.shards.insert(id, (shard, 0));
+    }
+
+    fn next_id(&mut self) -> u32 {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    /// the group's shard.
+    pub(crate) fn get(&self, gid: &GroupId) -> Result<Shard> {
+        self.groups
+            .get(gid)
+            .and_then(|id| self.shards.get(id))
+            .map(|(shard, _)| shard.clone())
+            .ok_or(anyhow!("Group missing"))
+    }
+
+    /// all groups' shards.
+    pub(crate) fn all(&self) -> Vec<Shard> {
+        self.shards
+            .values()
+            .map(|(shard, _)| shard.clone())
+            .collect()
+    }
+
+    /// the group's shard id, new group will have new shard.
+    /// the shard checks the creation, and retires when it failed.
+    fn create(&mut self, gid: GroupId) -> u32 {
+        if let Some(id) = self.groups.get(&gid) {
+            return *id;
+        }
+
+        let id = self.next_id();
+        let layer = self.layer.shard(id);
+        self.spawn(id, layer);
+        self.groups.insert(gid, id);
+        id
+    }
+
+    /// remove the shards without group, when no message waiting in them.
+    fn retire(&mut self) {
+        while let Ok((id, handled)) = self.retire.1.try_recv() {
+            if self.shards.get(&id).map(|(_, sent)| *sent) != Some(handled) {
+                continue;
+            }
+            // closed channel stops the shard's task.
+            self.shards.remove(&id);
+            self.groups.retain(|_, v| *v != id);
+            for ids in self.addrs.values_mut() {
+                ids.remove(&id);
+            }
+        }
+    }
+
+    /// dispatch the message to the shard which the message belongs to.
+    pub(crate) fn route(&mut self, gid: GroupId, msg: RecvType) {
+        self.retire();
+
+        match msg {
+            RecvType::Connect(addr, ref data) => {
+                let id = bincode::deserialize(data)
+                    .ok()
+                    .and_then(|LayerConnect(gcd, _)| self.groups.get(&gcd).copied())
+                    .unwrap_or(0);
+                self.dispatch(id, addr, gid, msg);
+            }
+            RecvType::Leave(addr) => {
+                for id in self.addrs.remove(&addr).unwrap_or_default() {
+                    self.send(id, Job::Handle(gid, RecvType::Leave(addr)));
+                }
+            }
+            RecvType::Event(addr, ref bytes) => {
+                let id = match bincode::deserialize(bytes) {
+                    Ok(LayerEvent::Heartbeat) => {
+                        // device is alive in all groups.
+                        for id in self.addrs.get(&addr).cloned().unwrap_or_default() {
+                            self.send(id, Job::Touch(addr));
+                        }
+                        return;
+                    }
+                    Ok(LayerEvent::Create(info, _)) => match info {
+                        GroupInfo::Common(_, _, _, gcd, ..) => self.create(gcd),
+                        GroupInfo::Encrypted(gcd, ..) => self.create(gcd),
+                    },
+                    Ok(event) => event_group(&event)
+                        .and_then(|gcd| self.groups.get(&gcd).copied())
+                        .unwrap_or(0),
+                    Err(_) => 0,
+                };
+                self.dispatch(id, addr, gid, msg);
+            }
+            RecvType::Stream(_, _, ref bytes) => {
+                let token = match bincode::deserialize(bytes) {
+                    Ok(LayerStream::Upload(token, ..)) => token,
+                    Ok(LayerStream::Download(token, ..)) => token,
+                    _ => return,
+                };
+                self.send(token_shard(&token), Job::Handle(gid, msg));
+            }
+            RecvType::Delivery(_, tid, _) => {
+                self.send(tid_shard(tid), Job::Handle(gid, msg));
+            }
+            RecvType::Result(..) => {}        // no-reach here.
+            RecvType::ResultConnect(..) => {} // no-reach here.
+        }
+    }
+
+    /// send the device's message to the shard, other shards know it is alive.
+    fn dispatch(&mut self, id: u32, addr: PeerAddr, gid: GroupId, msg: RecvType) {
+        let ids = self.addrs.entry(addr).or_default();
+        for other in ids.iter() {
+            if *other != id {
+                if let Some((shard, _)) = self.shards.get(other) {
+                    shard.send(Job::Touch(addr));
+                }
+            }
+        }
+        if id != 0 {
+            ids.insert(id);
+        }
+        self.send(id, Job::Handle(gid, msg));
+    }
+
+    fn send(&mut self, id: u32, job: Job) {
+        let (shard, sent) = if let Some(v) = self.shards.get_mut(&id) {
+            v
+        } else {
+            return;
+        };
+
+        match job {
+            // devices and cursors changed by them, never dropped.
+            Job::Handle(_, RecvType::Leave(..)) | Job::Handle(_, RecvType::Delivery(..)) => {
+                shard.send_wait(job);
+                *sent += 1;
+            }
+            Job::Handle(gid, msg) => match shard.0.try_send(Job::Handle(gid, msg)) {
+                Ok(()) => *sent += 1,
+                Err(TrySendError::Full(Job::Handle(gid, msg))) => {
+                    println!("shard busy, message rejected.");
+                    let results = busy(gid, msg);
+                    let sender = self.sender.clone();
+                    tokio::spawn(async move { crate::handle(results, 0, &sender).await });
+                }
+                Err(_) => {}
+            },
+            job => shard.send(job),
+        }
+    }
+
+    /// offline the idle devices in all shards.
+    pub(crate) fn sweep(&mut self, timeout: Duration) {
+        self.retire();
+        for (shard, _) in self.shards.values() {
+            shard.send(Job::Sweep(timeout));
+        }
+    }
+
+    /// network lost, all devices are offline.
+    pub(crate) fn network_lost(&mut self) {
+        for (shard, _) in self.shards.values() {
+            shard.send_wait(Job::NetworkLost);
+        }
+        self.addrs.clear();
+    }
+}
+
+/// the shard id which issued the delivery id, it is the high bits.
+pub(crate) fn tid_shard(tid: u64) -> u32 {
+    (tid >> 32) as u32
+}
+
+/// tell the sender its message rejected, the shard is busy.
+fn busy(gid: GroupId, msg: RecvType) -> HandleResult {
+    let mut results = HandleResult::new();
+    let reason = "server busy.".to_owned();
+    match msg {
+        RecvType::Connect(addr, _) => {
+            let s = SendType::Result(0, addr, false, false, vec![]);
+            add_layer(&mut results, gid, s);
+        }
+        RecvType::Event(addr, bytes) => {
+            let event = match bincode::deserialize(&bytes) {
+                Ok(LayerEvent::Sync(gcd, _, Event::MessageCreate(_, _, datetime))) => {
+                    LayerEvent::MessageReject(gcd, datetime, reason)
+                }
+                Ok(LayerEvent::UploadReq(gcd, hash, _))
+                | Ok(LayerEvent::DownloadReq(gcd, hash))
+                | Ok(LayerEvent::AttachmentReq(gcd, hash)) => {
+                    LayerEvent::TransferReject(gcd, hash, reason)
+                }
+                Ok(LayerEvent::Request(gcd, _)) => LayerEvent::Reject(gcd, false),
+                Ok(LayerEvent::Create(GroupInfo::Common(_, _, _, gcd, ..), _))
+                | Ok(LayerEvent::Create(GroupInfo::Encrypted(gcd, ..), _)) => {
+                    LayerEvent::CreateResult(gcd, false)
+                }
+                _ => return results,
+            };
+            let data = bincode::serialize(&event).unwrap_or(vec![]);
+            add_layer(&mut results, gid, SendType::Event(0, addr, data));
+        }
+        RecvType::Stream(uid, stream, bytes) => {
+            let token = match bincode::deserialize(&bytes) {
+                Ok(LayerStream::Upload(token, ..)) | Ok(LayerStream::Download(token, ..)) => token,
+                _ => return results,
+            };
+            let data = bincode::serialize(&LayerStream::Error(token, reason)).unwrap_or(vec![]);
+            add_layer(&mut results, gid, SendType::Stream(uid, stream, data));
+        }
+        _ => {}
+    }
+    results
+}
+
+/// the group which the event belongs to.
+pub(crate) fn event_group(event: &LayerEvent) -> Option<GroupId> {
+    match event {
+        LayerEvent::Offline(gcd)
+        | LayerEvent::Suspend(gcd)
+        | LayerEvent::Actived(gcd)
+        | LayerEvent::Request(gcd, ..)
+        | LayerEvent::RequestResult(gcd, ..)
+        | LayerEvent::Sync(gcd, ..)
+        | LayerEvent::SyncReq(gcd, ..)
+        | LayerEvent::SyncLazyReq(gcd, ..)
+        | LayerEvent::AttachmentReq(gcd, ..)
+        | LayerEvent::ThumbReq(gcd, ..)
+        | LayerEvent::MemberAvatar(gcd, ..)
+        | LayerEvent::MemberOnlineSync(gcd)
+        | LayerEvent::UploadReq(gcd, ..)
+        | LayerEvent::DownloadReq(gcd, ..) => Some(*gcd),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layer::tests::{account, layer, running_group, PROVIDER};
+    use group_chat_types::{GroupType, NetworkMessage};
+    use std::sync::{Arc, Mutex};
+    use tdn_did::Proof;
+
+    fn shards(layer: Layer) -> (Shards, Receiver<SendMessage>) {
+        let (sender, recver) = channel(64);
+        (Shards::new(layer, sender), recver)
+    }
+
+    /// wait all jobs before it handled.
+    async fn flush(shard: &Shard) {
+        shard.call(|_| Box::pin(async { Ok(()) })).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn shard_call() {
+        let mut layer = layer();
+        let gcd = running_group(&mut layer);
+        let (shards, _recver) = shards(layer);
+
+        let shard = shards.get(&gcd).unwrap();
+        let gids = shard
+            .call(|layer| Box::pin(async move { Ok(layer.gids()) }))
+            .await
+            .unwrap();
+        assert_eq!(gids, vec![gcd]);
+
+        let res: Result<()> = shard
+            .call(|_| Box::pin(async { Err(anyhow!("failed.")) }))
+            .await;
+        assert!(res.is_err());
+    }
+
+    #[tokio::test]
+    async fn group_jobs_in_order() {
+        let mut layer = layer();
+        let gcd = running_group(&mut layer);
+        let (shards, _recver) = shards(layer);
+        let shard = shards.get(&gcd).unwrap();
+
+        let order = Arc::new(Mutex::new(vec![]));
+        for i in 0..3u64 {
+            let order = order.clone();
+            shard.send(Job::Call(boxed(move |_| {
+                Box::pin(async move {
+                    // earlier job is slower, later jobs still wait it.
+                    tokio::time::sleep(Duration::from_millis(30 - i * 10)).await;
+                    order.lock().unwrap().push(i);
+                })
+            })));
+        }
+        flush(&shard).await;
+        assert_eq!(*order.lock().unwrap(), vec![0, 1, 2]);
+    }
+
+    #[tokio::test]
+    async fn failed_create_retires_shard() {
+        let (mut shards, _recver) = shards(layer());
+        let (gid, key) = account(1);
+        let addr = PeerAddr([2u8; 32]);
+        let gcd = GroupId([1u8; 32]);
+
+        // proof signed to other address, creation denied.
+        let proof = Proof::prove(&key, &PeerAddr([3u8; 32]), &PROVIDER);
+        let info = GroupInfo::Common(
+            gid,
+            "".to_owned(),
+            vec![],
+            gcd,
+            GroupType::Open,
+            false,
+            "".to_owned(),
+            "".to_owned(),
+            vec![],
+        );
+        let data = bincode::serialize(&LayerEvent::Create(info, proof)).unwrap();
+        shards.route(gid, RecvType::Event(addr, data.clone()));
+        shards.route(gid, RecvType::Event(addr, data));
+
+        let shard = shards.get(&gcd).unwrap();
+        flush(&shard).await;
+
+        shards.retire();
+        assert!(shards.get(&gcd).is_err());
+        assert_eq!(shards.all().len(), 1);
+        assert!(shards.addrs.get(&addr).unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn busy_shard_not_retired() {
+        let (mut shards, _recver) = shards(layer());
+        let id = shards.create(GroupId([1u8; 32]));
+
+        // shard reported before the latest message handled.
+        shards.shards.get_mut(&id).unwrap().1 = 2;
+        shards.retire.0.try_send((id, 1)).unwrap();
+        shards.retire();
+        assert!(shards.get(&GroupId([1u8; 32])).is_ok());
+    }
+
+    #[tokio::test]
+    async fn full_shard_rejects_events_keeps_deliveries() {
+        let mut layer = layer();
+        let gcd = running_group(&mut layer);
+        let (mut shards, mut recver) = shards(layer);
+        let id = shards.groups[&gcd];
+        let shard = shards.get(&gcd).unwrap();
+
+        // shard is blocked, and its queue is full.
+        let (unblock, blocked) = oneshot::channel::<()>();
+        shard.send(Job::Call(boxed(move |_| {
+            Box::pin(async move {
+                let _ = blocked.await;
+            })
+        })));
+        for _ in 0..SHARD_QUEUE {
+            shard.send(Job::Touch(PeerAddr([2u8; 32])));
+        }
+
+        let (gid, addr) = (GroupId([2u8; 32]), PeerAddr([2u8; 32]));
+        let msg = Event::MessageCreate(gid, NetworkMessage::String("".into()), 7);
+        let data = bincode::serialize(&LayerEvent::Sync(gcd, 0, msg)).unwrap();
+        shards.route(gid, RecvType::Event(addr, data));
+        shards.route(gid, RecvType::Delivery(0, (id as u64) << 32 | 1, true));
+        assert_eq!(shards.shards[&id].1, 1);
+
+        let reply = match recver.recv().await {
+            Some(SendMessage::Layer(_, tgid, SendType::Event(_, taddr, data))) => {
+                assert_eq!((tgid, taddr), (gid, addr));
+                bincode::deserialize(&data).unwrap()
+            }
+            _ => panic!("no reply."),
+        };
+        assert!(matches!(reply, LayerEvent::MessageReject(g, 7, _) if g == gcd));
+
+        // delivery waits the queue.
+        let _ = unblock.send(());
+        flush(&shard).await;
+        assert!(recver.try_recv().is_err());
+    }
+
+    #[test]
+    fn event_in_group() {
+        let gcd = GroupId([1u8; 32]);
+        assert_eq!(event_group(&LayerEvent::Offline(gcd)), Some(gcd));
+        assert_eq!(event_group(&LayerEvent::Check), None);
+        assert_eq!(event_group(&LayerEvent::Heartbeat), None);
+    }
+}
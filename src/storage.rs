@@ -1,16 +1,21 @@
 use config::Config as CFG;
 use dotenv::dotenv;
-use image::{load_from_memory, DynamicImage, GenericImageView};
+use image::{guess_format, load_from_memory, DynamicImage, GenericImageView, ImageOutputFormat};
 use once_cell::sync::OnceCell;
-use rand::{distributions::Alphanumeric, thread_rng, Rng};
 use serde::Deserialize;
 use sqlx::postgres::PgPoolOptions;
-use sqlx::{PgPool, Pool, Postgres};
+use sqlx::{PgPool, Pool, Postgres, Transaction};
 use std::env;
+use std::io::{Cursor, SeekFrom};
 use std::path::PathBuf;
-use std::time::{SystemTime, UNIX_EPOCH};
 use tdn::types::{group::GroupId, primitive::Result};
 use tokio::fs;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+use crate::{
+    DEFAULT_FILE_SIZE_LIMIT, DEFAULT_GROUP_QUOTA, DEFAULT_MEMBER_DAILY_QUOTA,
+    DEFAULT_MESSAGE_SIZE_LIMIT,
+};
 
 #[derive(Debug, Deserialize)]
 struct Config {
@@ -38,11 +43,65 @@ impl Config {
 
 pub static INSTANCE: OnceCell<Pool<Postgres>> = OnceCell::new();
 
+/// upload limits, all in bytes.
+pub(crate) struct Limits {
+    /// max size of one message.
+    pub message_size: i64,
+    /// max size of one streamed file.
+    pub file_size: i64,
+    /// max size of all files in one group.
+    pub group_quota: i64,
+    /// max size that a member can upload in one day.
+    pub member_daily: i64,
+}
+
+pub static LIMITS: OnceCell<Limits> = OnceCell::new();
+
+#[inline]
+pub(crate) fn limits<'a>() -> &'a Limits {
+    LIMITS.get_or_init(Limits::from_env)
+}
+
+impl Limits {
+    /// change by env: MESSAGE_SIZE_LIMIT, FILE_SIZE_LIMIT, GROUP_QUOTA, MEMBER_DAILY_QUOTA.
+    fn from_env() -> Self {
+        Self::from_vars(|key| env::var(key).ok())
+    }
+
+    /// invalid or missing value uses the default.
+    fn from_vars(get: impl Fn(&str) -> Option<String>) -> Self {
+        let var = |key: &str, default: i64| {
+            get(key)
+                .and_then(|v| v.trim().parse().ok())
+                .filter(|v: &i64| *v > 0)
+                .unwrap_or(default)
+        };
+
+        Self {
+            message_size: var("MESSAGE_SIZE_LIMIT", DEFAULT_MESSAGE_SIZE_LIMIT),
+            file_size: var("FILE_SIZE_LIMIT", DEFAULT_FILE_SIZE_LIMIT),
+            group_quota: var("GROUP_QUOTA", DEFAULT_GROUP_QUOTA),
+            member_daily: var("MEMBER_DAILY_QUOTA", DEFAULT_MEMBER_DAILY_QUOTA),
+        }
+    }
+}
+
 #[inline]
 pub fn get_pool<'a>() -> Result<&'a PgPool> {
     INSTANCE.get().ok_or(anyhow!("DB get error!"))
 }
 
+/// database transaction, event's row, consensus and height commit together.
+pub(crate) type Tx = Transaction<'static, Postgres>;
+
+#[inline]
+pub(crate) async fn begin() -> Result<Tx> {
+    get_pool()?
+        .begin()
+        .await
+        .map_err(|_| anyhow!("database failure."))
+}
+
 pub async fn init() -> Result<()> {
     dotenv().ok();
     let cfg = Config::from_env()?;
@@ -53,29 +112,40 @@ pub async fn init() -> Result<()> {
         .await
         .map_err(|_| anyhow!("DB postgres connect failure! check database & user/password"))?;
 
+    // embedded migrations, fresh database no need sqlx-cli.
+    sqlx::migrate!("./migrations")
+        .run(&pool)
+        .await
+        .map_err(|e| anyhow!("DB migrate failure! {}", e))?;
+
+    let limits = limits();
+    println!(
+        "upload limits: message {}, file {}, group {}, member daily {}.",
+        limits.message_size, limits.file_size, limits.group_quota, limits.member_daily
+    );
+
     INSTANCE.set(pool).map_err(|_| anyhow!("DB set error!"))
 }
 
-const FILES_DIR: &'static str = "files";
-const IMAGE_DIR: &'static str = "images";
+const BLOB_DIR: &'static str = "blobs";
 const THUMB_DIR: &'static str = "thumbs";
 const EMOJI_DIR: &'static str = "emojis";
-const RECORD_DIR: &'static str = "records";
-const AVATAR_DIR: &'static str = "avatars";
+
+/// before blob store, files saved by type and client-supplied name.
+/// only read when migrate the old files.
+pub(crate) const LEGACY_FILES_DIR: &'static str = "files";
+pub(crate) const LEGACY_IMAGE_DIR: &'static str = "images";
+pub(crate) const LEGACY_RECORD_DIR: &'static str = "records";
+pub(crate) const LEGACY_AVATAR_DIR: &'static str = "avatars";
 
 pub(crate) async fn init_local_files(base: &PathBuf, gid: &GroupId) -> Result<()> {
     let mut home = base.clone();
     home.push(gid.to_hex());
 
-    let mut files_path = home.clone();
-    files_path.push(FILES_DIR);
-    if !files_path.exists() {
-        fs::create_dir_all(files_path).await?;
-    }
-    let mut image_path = home.clone();
-    image_path.push(IMAGE_DIR);
-    if !image_path.exists() {
-        fs::create_dir_all(image_path).await?;
+    let mut blob_path = home.clone();
+    blob_path.push(BLOB_DIR);
+    if !blob_path.exists() {
+        fs::create_dir_all(blob_path).await?;
     }
     let mut thumb_path = home.clone();
     thumb_path.push(THUMB_DIR);
@@ -87,24 +157,51 @@ pub(crate) async fn init_local_files(base: &PathBuf, gid: &GroupId) -> Result<()
     if !emoji_path.exists() {
         fs::create_dir_all(emoji_path).await?;
     }
-    let mut record_path = home.clone();
-    record_path.push(RECORD_DIR);
-    if !record_path.exists() {
-        fs::create_dir_all(record_path).await?;
-    }
-    let mut avatar_path = home.clone();
-    avatar_path.push(AVATAR_DIR);
-    if !avatar_path.exists() {
-        fs::create_dir_all(avatar_path).await?;
-    }
     Ok(())
 }
 
-pub(crate) async fn read_file(base: &PathBuf, gid: &GroupId, name: &str) -> Result<Vec<u8>> {
+/// blob's name is the hex of blake3 hash, never use client-supplied name.
+pub(crate) fn is_blob_name(name: &str) -> bool {
+    name.len() == 64 && name.bytes().all(|c| c.is_ascii_hexdigit())
+}
+
+/// file name which safe to join in a directory.
+pub(crate) fn is_safe_name(name: &str) -> bool {
+    !name.is_empty() && !name.contains('/') && !name.contains('\\') && !name.contains("..")
+}
+
+fn blob_path(base: &PathBuf, gid: &GroupId, hash: &str) -> Result<PathBuf> {
+    if !is_blob_name(hash) {
+        return Err(anyhow!("blob name invalid."));
+    }
     let mut path = base.clone();
     path.push(gid.to_hex());
-    path.push(FILES_DIR);
-    path.push(name);
+    path.push(BLOB_DIR);
+    path.push(hash);
+    Ok(path)
+}
+
+/// save bytes to group's blob store, return the blake3 hash hex.
+/// same bytes only saved once.
+pub(crate) async fn write_blob(base: &PathBuf, gid: &GroupId, bytes: &[u8]) -> Result<String> {
+    let hash = blake3::hash(bytes).to_hex().to_string();
+    let path = blob_path(base, gid, &hash)?;
+    if !path.exists() {
+        // write to temp file first, never leave a broken blob.
+        let mut tmp = path.clone();
+        tmp.set_extension("tmp");
+        fs::write(&tmp, bytes).await?;
+        fs::rename(tmp, path).await?;
+    }
+    Ok(hash)
+}
+
+pub(crate) async fn read_blob(base: &PathBuf, gid: &GroupId, hash: &str) -> Result<Vec<u8>> {
+    // legacy name which file was missing when migrate, it never in blob store.
+    if !is_blob_name(hash) {
+        return Ok(vec![]);
+    }
+    let path = blob_path(base, gid, hash)?;
     if path.exists() {
         Ok(fs::read(path).await?)
     } else {
@@ -112,41 +209,97 @@ pub(crate) async fn read_file(base: &PathBuf, gid: &GroupId, name: &str) -> Resu
     }
 }
 
-pub(crate) async fn read_image(base: &PathBuf, gid: &GroupId, name: &str) -> Result<Vec<u8>> {
-    let mut path = base.clone();
-    path.push(gid.to_hex());
-    path.push(IMAGE_DIR);
-    path.push(name);
+pub(crate) async fn delete_blob(base: &PathBuf, gid: &GroupId, hash: &str) -> Result<()> {
+    let path = blob_path(base, gid, hash)?;
+    let thumb = thumb_path(base, gid, hash)?;
+    if thumb.exists() {
+        fs::remove_file(thumb).await?;
+    }
     if path.exists() {
-        Ok(fs::read(path).await?)
+        Ok(fs::remove_file(path).await?)
     } else {
-        Ok(vec![])
+        Ok(())
+    }
+}
+
+/// the uploading blob, chunks append to it.
+fn part_path(base: &PathBuf, gid: &GroupId, hash: &str) -> Result<PathBuf> {
+    let mut path = blob_path(base, gid, hash)?;
+    path.set_extension("part");
+    Ok(path)
+}
+
+/// uploaded bytes of the uploading blob.
+pub(crate) async fn part_len(base: &PathBuf, gid: &GroupId, hash: &str) -> Result<u64> {
+    let path = part_path(base, gid, hash)?;
+    if path.exists() {
+        Ok(fs::metadata(path).await?.len())
+    } else {
+        Ok(0)
     }
 }
 
-pub(crate) async fn write_file(
+pub(crate) async fn append_part(
     base: &PathBuf,
     gid: &GroupId,
-    name: &str,
+    hash: &str,
     bytes: &[u8],
-) -> Result<String> {
-    let mut path = base.clone();
-    path.push(gid.to_hex());
-    path.push(FILES_DIR);
-    path.push(name);
-    fs::write(path, bytes).await?;
-    Ok(name.to_owned())
+) -> Result<()> {
+    let path = part_path(base, gid, hash)?;
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await?;
+    file.write_all(bytes).await?;
+    Ok(file.flush().await?)
 }
 
-#[inline]
-fn image_name() -> String {
-    let mut name: String = thread_rng()
-        .sample_iter(&Alphanumeric)
-        .take(20)
-        .map(char::from)
-        .collect();
-    name.push_str(".png");
-    name
+/// check the uploaded blob's hash, and move it to blob store.
+/// when hash not match, the broken upload will deleted.
+pub(crate) async fn finish_part(base: &PathBuf, gid: &GroupId, hash: &str) -> Result<bool> {
+    let path = part_path(base, gid, hash)?;
+    let mut file = fs::File::open(&path).await?;
+    let mut hasher = blake3::Hasher::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+
+    if hasher.finalize().to_hex().as_str() == hash {
+        fs::rename(path, blob_path(base, gid, hash)?).await?;
+        Ok(true)
+    } else {
+        fs::remove_file(path).await?;
+        Ok(false)
+    }
+}
+
+/// read a part of blob, for streamed download.
+pub(crate) async fn read_blob_chunk(
+    base: &PathBuf,
+    gid: &GroupId,
+    hash: &str,
+    offset: u64,
+    len: u64,
+) -> Result<Vec<u8>> {
+    // same as read_blob, lost file is empty.
+    if !is_blob_name(hash) {
+        return Ok(vec![]);
+    }
+    let path = blob_path(base, gid, hash)?;
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+    let mut file = fs::File::open(path).await?;
+    file.seek(SeekFrom::Start(offset)).await?;
+    let mut bytes = vec![];
+    file.take(len).read_to_end(&mut bytes).await?;
+    Ok(bytes)
 }
 
 #[inline]
@@ -161,118 +314,124 @@ fn image_thumb(bytes: &[u8]) -> Result<DynamicImage> {
     }
 }
 
-pub(crate) async fn write_image(base: &PathBuf, gid: &GroupId, bytes: &[u8]) -> Result<String> {
+fn thumb_path(base: &PathBuf, gid: &GroupId, hash: &str) -> Result<PathBuf> {
+    if !is_blob_name(hash) {
+        return Err(anyhow!("blob name invalid."));
+    }
     let mut path = base.clone();
     path.push(gid.to_hex());
-
-    let thumb = image_thumb(bytes)?;
-    let name = image_name();
-
-    let mut thumb_path = path.clone();
-    thumb_path.push(THUMB_DIR);
-    thumb_path.push(name.clone());
-    tokio::spawn(async move {
-        let _ = thumb.save(thumb_path);
-    });
-
-    path.push(IMAGE_DIR);
-    path.push(name.clone());
-    fs::write(path, bytes).await?;
-
-    Ok(name)
+    path.push(THUMB_DIR);
+    path.push(format!("{}.png", hash));
+    Ok(path)
 }
 
-#[inline]
-fn avatar_png(gid: &GroupId) -> String {
-    let mut gs = gid.to_hex();
-    gs.push_str(".png");
-    gs
-}
-
-pub(crate) async fn read_avatar(
+/// save image's thumbnail, named by the image's blob hash, return the png bytes.
+/// it is saved before return, so failure can be known and retried.
+pub(crate) async fn write_thumb(
     base: &PathBuf,
     gid: &GroupId,
-    remote: &GroupId,
+    hash: &str,
+    bytes: &[u8],
 ) -> Result<Vec<u8>> {
-    let mut path = base.clone();
-    path.push(gid.to_hex());
-    path.push(AVATAR_DIR);
-    path.push(avatar_png(remote));
+    let path = thumb_path(base, gid, hash)?;
+
+    // decode and resize is heavy, not block the runtime.
+    let bytes = bytes.to_vec();
+    let png = tokio::task::spawn_blocking(move || -> Result<Vec<u8>> {
+        let thumb = image_thumb(&bytes)?;
+        let mut png = Cursor::new(vec![]);
+        thumb
+            .write_to(&mut png, ImageOutputFormat::Png)
+            .map_err(|_| anyhow!("image thumbnail failure."))?;
+        Ok(png.into_inner())
+    })
+    .await
+    .map_err(|_| anyhow!("image thumbnail failure."))??;
+
+    fs::write(path, &png).await?;
+    Ok(png)
+}
+
+/// image's thumbnail, regenerate it when missing.
+/// empty when the image missing.
+pub(crate) async fn read_thumb(base: &PathBuf, gid: &GroupId, hash: &str) -> Result<Vec<u8>> {
+    let path = thumb_path(base, gid, hash)?;
     if path.exists() {
-        Ok(fs::read(path).await?)
-    } else {
-        Ok(vec![])
+        return Ok(fs::read(path).await?);
     }
-}
 
-pub(crate) async fn write_avatar(
-    base: &PathBuf,
-    gid: &GroupId,
-    remote: &GroupId,
-    bytes: &Vec<u8>,
-) -> Result<()> {
-    if bytes.len() < 1 {
-        return Ok(());
+    let bytes = read_blob(base, gid, hash).await?;
+    if bytes.is_empty() {
+        return Ok(vec![]);
     }
-    let mut path = base.clone();
-    path.push(gid.to_hex());
-    path.push(AVATAR_DIR);
-    path.push(avatar_png(remote));
-    Ok(fs::write(path, bytes).await?)
+    println!("regenerate thumbnail: {}", hash);
+    write_thumb(base, gid, hash, &bytes).await
 }
 
-pub(crate) async fn delete_avatar(base: &PathBuf, gid: &GroupId, remote: &GroupId) -> Result<()> {
-    let mut path = base.clone();
-    path.push(gid.to_hex());
-    path.push(AVATAR_DIR);
-    path.push(avatar_png(remote));
-    if path.exists() {
-        Ok(fs::remove_file(path).await?)
-    } else {
-        Ok(())
+/// image's mime from its header bytes.
+pub(crate) fn image_mime(header: &[u8]) -> String {
+    match guess_format(header) {
+        Ok(format) => format!("image/{}", format.extensions_str()[0]),
+        Err(_) => "image/*".to_owned(),
     }
 }
 
-pub(crate) async fn read_record(base: &PathBuf, gid: &GroupId, name: &str) -> Result<Vec<u8>> {
+/// read the old file which saved before blob store, None if missing.
+pub(crate) async fn read_legacy(
+    base: &PathBuf,
+    gid: &GroupId,
+    dir: &str,
+    name: &str,
+) -> Result<Option<(PathBuf, Vec<u8>)>> {
+    if !is_safe_name(name) {
+        return Ok(None);
+    }
     let mut path = base.clone();
     path.push(gid.to_hex());
-    path.push(RECORD_DIR);
+    path.push(dir);
     path.push(name);
     if path.exists() {
-        Ok(fs::read(path).await?)
+        let bytes = fs::read(&path).await?;
+        Ok(Some((path, bytes)))
     } else {
-        Ok(vec![])
+        Ok(None)
     }
 }
 
-pub(crate) async fn write_record(
+/// list the old avatars, (avatar's owner, file path).
+pub(crate) async fn list_legacy_avatars(
     base: &PathBuf,
     gid: &GroupId,
-    fid: &i64,
-    t: &u32,
-    bytes: &Vec<u8>,
-) -> Result<String> {
-    let start = SystemTime::now();
-    let datetime = start
-        .duration_since(UNIX_EPOCH)
-        .map(|s| s.as_millis())
-        .unwrap_or(0u128);
-
+) -> Result<Vec<(GroupId, PathBuf)>> {
     let mut path = base.clone();
     path.push(gid.to_hex());
-    path.push(RECORD_DIR);
-    path.push(format!("{}_{}.m4a", fid, datetime));
-    fs::write(path, bytes).await?;
+    path.push(LEGACY_AVATAR_DIR);
+    let mut avatars = vec![];
+    if !path.exists() {
+        return Ok(avatars);
+    }
 
-    Ok(format!("{}-{}_{}.m4a", t, fid, datetime))
+    let mut dir = fs::read_dir(path).await?;
+    while let Some(entry) = dir.next_entry().await? {
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if let Some(hex) = name.strip_suffix(".png") {
+            if let Ok(rid) = GroupId::from_hex(hex) {
+                avatars.push((rid, entry.path()));
+            }
+        }
+    }
+    Ok(avatars)
 }
 
-pub(crate) async fn _delete_record(base: &PathBuf, gid: &GroupId, name: &str) -> Result<()> {
-    let mut path = base.clone();
-    path.push(gid.to_hex());
-    path.push(RECORD_DIR);
-    path.push(name);
-    Ok(fs::remove_file(path).await?)
+/// parse record content, (time, blob hash).
+pub(crate) fn parse_record(content: &str) -> Option<(u32, &str)> {
+    let (t, name) = content.split_once('-')?;
+    let time = t.parse().ok()?;
+    if !is_safe_name(name) {
+        return None;
+    }
+    Some((time, name))
 }
 
 pub(crate) fn _write_emoji(base: &PathBuf, gid: &GroupId) -> Result<()> {
@@ -281,3 +440,65 @@ pub(crate) fn _write_emoji(base: &PathBuf, gid: &GroupId) -> Result<()> {
     path.push(EMOJI_DIR);
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits_default() {
+        let limits = Limits::from_vars(|_| None);
+        assert_eq!(limits.message_size, DEFAULT_MESSAGE_SIZE_LIMIT);
+        assert_eq!(limits.file_size, DEFAULT_FILE_SIZE_LIMIT);
+        assert_eq!(limits.group_quota, DEFAULT_GROUP_QUOTA);
+        assert_eq!(limits.member_daily, DEFAULT_MEMBER_DAILY_QUOTA);
+    }
+
+    #[test]
+    fn limits_from_vars() {
+        let limits = Limits::from_vars(|key| match key {
+            "MESSAGE_SIZE_LIMIT" => Some("1024".to_owned()),
+            "FILE_SIZE_LIMIT" => Some("8192".to_owned()),
+            "GROUP_QUOTA" => Some(" 4096 ".to_owned()),
+            "MEMBER_DAILY_QUOTA" => Some("2048".to_owned()),
+            _ => None,
+        });
+        assert_eq!(limits.message_size, 1024);
+        assert_eq!(limits.file_size, 8192);
+        assert_eq!(limits.group_quota, 4096);
+        assert_eq!(limits.member_daily, 2048);
+    }
+
+    #[tokio::test]
+    async fn lost_blob_read_empty() {
+        let base = std::env::temp_dir().join(format!("dao-lost-{}", std::process::id()));
+        let gcd = GroupId([1u8; 32]);
+        init_local_files(&base, &gcd).await.unwrap();
+        let hash = write_blob(&base, &gcd, b"blob").await.unwrap();
+        let lost = blake3::hash(b"lost").to_hex().to_string();
+
+        let chunk = read_blob_chunk(&base, &gcd, &hash, 1, 2).await.unwrap();
+        let missing = read_blob_chunk(&base, &gcd, &lost, 0, 32).await.unwrap();
+        let legacy = read_blob_chunk(&base, &gcd, "old.png", 0, 32)
+            .await
+            .unwrap();
+        let _ = tokio::fs::remove_dir_all(&base).await;
+
+        assert_eq!(chunk, b"lo");
+        assert!(missing.is_empty());
+        assert!(legacy.is_empty());
+    }
+
+    #[test]
+    fn limits_invalid_use_default() {
+        let limits = Limits::from_vars(|key| match key {
+            "MESSAGE_SIZE_LIMIT" => Some("10MB".to_owned()),
+            "GROUP_QUOTA" => Some("-1".to_owned()),
+            "MEMBER_DAILY_QUOTA" => Some("0".to_owned()),
+            _ => None,
+        });
+        assert_eq!(limits.message_size, DEFAULT_MESSAGE_SIZE_LIMIT);
+        assert_eq!(limits.group_quota, DEFAULT_GROUP_QUOTA);
+        assert_eq!(limits.member_daily, DEFAULT_MEMBER_DAILY_QUOTA);
+    }
+}
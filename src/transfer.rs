@@ -0,0 +1,145 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+use tdn::types::{
+    group::GroupId,
+    primitive::{PeerAddr, Result},
+};
+
+/// streamed upload or download session.
+/// client request it by event, then use the token in stream.
+pub(crate) struct Transfer {
+    /// group chat id.
+    pub gcd: GroupId,
+    /// group's db id.
+    pub fid: i64,
+    /// member's db id, uploaded bytes count to it.
+    pub mid: i64,
+    /// requester's network id and address, stream must from it.
+    pub gid: GroupId,
+    pub addr: PeerAddr,
+    /// blob's hash hex.
+    pub hash: String,
+    /// blob's total size.
+    pub size: u64,
+    /// upload or download.
+    pub is_upload: bool,
+}
+
+impl Transfer {
+    /// member uploads the blob, from: requester's network id and address.
+    pub fn upload(
+        gcd: GroupId,
+        fid: i64,
+        mid: i64,
+        from: (GroupId, PeerAddr),
+        hash: String,
+        size: u64,
+    ) -> Self {
+        Self {
+            gcd,
+            fid,
+            mid,
+            gid: from.0,
+            addr: from.1,
+            hash,
+            size,
+            is_upload: true,
+        }
+    }
+
+    /// download the blob, from: requester's network id and address.
+    pub fn download(
+        gcd: GroupId,
+        fid: i64,
+        from: (GroupId, PeerAddr),
+        hash: String,
+        size: u64,
+    ) -> Self {
+        Self {
+            gcd,
+            fid,
+            mid: 0,
+            gid: from.0,
+            addr: from.1,
+            hash,
+            size,
+            is_upload: false,
+        }
+    }
+
+    /// session token, unique in running.
+    /// the head 4 bytes are the shard id, streams route by it.
+    pub fn token(&self, shard: u32) -> [u8; 32] {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|s| s.as_nanos())
+            .unwrap_or(0);
+
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(&self.gcd.0);
+        hasher.update(&self.gid.0);
+        hasher.update(self.hash.as_bytes());
+        hasher.update(&nanos.to_le_bytes());
+        let mut token = *hasher.finalize().as_bytes();
+        token[..4].copy_from_slice(&shard.to_be_bytes());
+        token
+    }
+
+    /// number of chunks.
+    pub fn chunks(&self, chunk_size: u64) -> u64 {
+        (self.size + chunk_size - 1) / chunk_size
+    }
+}
+
+/// file hash from client, 32 bytes blake3.
+pub(crate) fn hash_hex(hash: &[u8]) -> Result<String> {
+    if hash.len() != 32 {
+        return Err(anyhow!("hash invalid."));
+    }
+    Ok(hex::encode(hash))
+}
+
+/// the shard id which issued the session token.
+pub(crate) fn token_shard(token: &[u8; 32]) -> u32 {
+    u32::from_be_bytes([token[0], token[1], token[2], token[3]])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// empty upload session.
+    fn transfer() -> Transfer {
+        let from = (GroupId::default(), PeerAddr::default());
+        Transfer::upload(GroupId::default(), 1, 1, from, "".to_owned(), 0)
+    }
+
+    #[test]
+    fn hash_hex_only_blake3() {
+        let hash = blake3::hash(b"file");
+        assert_eq!(
+            hash_hex(hash.as_bytes()).unwrap(),
+            hash.to_hex().to_string()
+        );
+        assert!(hash_hex(&[0u8; 31]).is_err());
+        assert!(hash_hex(&[0u8; 33]).is_err());
+        assert!(hash_hex(&[]).is_err());
+    }
+
+    #[test]
+    fn token_has_shard() {
+        let t = transfer();
+        assert_eq!(token_shard(&t.token(7)), 7);
+        assert_eq!(token_shard(&t.token(u32::MAX)), u32::MAX);
+        assert_ne!(t.token(7), t.token(7));
+    }
+
+    #[test]
+    fn chunks_round_up() {
+        let mut t = transfer();
+        assert_eq!(t.chunks(256), 0);
+        t.size = 256;
+        assert_eq!(t.chunks(256), 1);
+        t.size = 257;
+        assert_eq!(t.chunks(256), 2);
+    }
+}
@@ -0,0 +1,56 @@
+use tdn::types::{group::GroupId, primitive::Result};
+
+/// Anonymous membership proof verifier.
+/// Member proves that it belongs to the group without revealing the Did,
+/// the server only knows an anonymous id which derived from the proof.
+pub(crate) trait ZkpVerifier: Send + Sync {
+    /// verify the membership proof of the group, return member's anonymous id.
+    fn verify(&self, gcd: &GroupId, proof: &[u8]) -> Result<GroupId>;
+}
+
+/// default verifier, reject all proofs when no proof system plugged.
+pub(crate) struct NoneVerifier;
+
+impl ZkpVerifier for NoneVerifier {
+    fn verify(&self, _gcd: &GroupId, _proof: &[u8]) -> Result<GroupId> {
+        Err(anyhow!("zkp not supported."))
+    }
+}
+
+/// mock verifier for offline develop, accept every non-empty proof,
+/// and the anonymous id is the proof's hash in the group.
+#[cfg(any(test, feature = "mock-zkp"))]
+pub(crate) struct MockVerifier;
+
+#[cfg(any(test, feature = "mock-zkp"))]
+impl ZkpVerifier for MockVerifier {
+    fn verify(&self, gcd: &GroupId, proof: &[u8]) -> Result<GroupId> {
+        if proof.is_empty() {
+            return Err(anyhow!("zkp proof invalid."));
+        }
+
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(&gcd.0);
+        hasher.update(proof);
+        Ok(GroupId(*hasher.finalize().as_bytes()))
+    }
+}
+
+/// the verifier by name in config, "none" or "mock" (only with mock-zkp feature).
+pub(crate) fn verifier(name: &str) -> Result<Box<dyn ZkpVerifier>> {
+    match name {
+        "none" => Ok(Box::new(NoneVerifier)),
+        #[cfg(any(test, feature = "mock-zkp"))]
+        "mock" => Ok(Box::new(MockVerifier)),
+        _ => Err(anyhow!("zkp verifier not supported.")),
+    }
+}
+
+/// the verifier which layer used when start.
+pub(crate) fn default_verifier() -> Box<dyn ZkpVerifier> {
+    #[cfg(any(test, feature = "mock-zkp"))]
+    return Box::new(MockVerifier);
+
+    #[cfg(not(any(test, feature = "mock-zkp")))]
+    return Box::new(NoneVerifier);
+}